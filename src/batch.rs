@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::cache::Cache;
+use crate::capabilities::{AspectRatio, Size};
+use crate::cli::Cli;
+use crate::client::{check_ignored_parameters, create_client, generate_batch};
+use crate::error::{error_code, CodedError, ErrorCode};
+use crate::output::ErrorBody;
+use crate::services::{ModelId, ServiceId};
+use crate::sidecar::write_sidecar;
+use crate::template::LINE;
+use crate::utils::{check_path_format, numbered_path, write_images};
+
+/// A prompt from a prompts file with optional per-line overrides
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PromptEntry {
+    #[serde(skip)]
+    pub line: usize,
+    pub prompt: String,
+    pub model: Option<ModelId>,
+    pub service: Option<ServiceId>,
+    pub seed: Option<u64>,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub size: Option<Size>,
+    pub aspect: Option<AspectRatio>,
+    pub steps: Option<u8>,
+    pub cfg: Option<f32>,
+    pub negative_prompt: Option<String>,
+    pub init_image: Option<PathBuf>,
+    pub strength: Option<f32>,
+    pub mask: Option<PathBuf>,
+    pub out: Option<String>,
+}
+
+impl PromptEntry {
+    /// Apply the overrides to a copy of the command line arguments
+    /// Without an `out` override, the output path is numbered by line (e.g., `image-001.png`) unless it has a
+    /// `{line}` placeholder, which becomes the line number instead (in overrides too)
+    /// `{n}` is left for the image number
+    pub fn apply(
+        self,
+        cli: &Cli,
+    ) -> Cli {
+        let mut cli = cli.clone();
+        cli.prompt = Some(self.prompt);
+        cli.out = match self.out {
+            Some(out) => out.replace(LINE, &format!("{:03}", self.line)),
+            None if cli.out.contains(LINE) => cli.out.replace(LINE, &format!("{:03}", self.line)),
+            None => numbered_path(&cli.out, self.line),
+        };
+        cli.model = self.model.or(cli.model);
+        cli.service = self.service.or(cli.service);
+        cli.seed = self.seed.or(cli.seed);
+        // Each way of setting the size replaces the others from the command line
+        if self.width.is_some() || self.height.is_some() {
+            cli.width = self.width.or(cli.width);
+            cli.height = self.height.or(cli.height);
+            (cli.size, cli.aspect) = (None, None);
+        } else if self.size.is_some() || self.aspect.is_some() {
+            (cli.width, cli.height) = (None, None);
+            (cli.size, cli.aspect) = (self.size, self.aspect);
+        }
+        cli.steps = self.steps.or(cli.steps);
+        cli.cfg = self.cfg.or(cli.cfg);
+        cli.negative_prompt = self.negative_prompt.or(cli.negative_prompt);
+        cli.init_image = self.init_image.or(cli.init_image);
+        cli.strength = self.strength.or(cli.strength);
+        cli.mask = self.mask.or(cli.mask);
+        cli
+    }
+}
+
+/// Read prompts from a text file (one prompt per line) or JSONL (one object per line)
+/// Blank lines and lines starting with `#` are skipped
+pub fn read_prompts_file(path: &Path) -> Result<Vec<PromptEntry>> {
+    let display = path.display();
+    let contents = fs::read_to_string(path).context(format!("Failed to read `{display}` (batch.rs)"))?;
+
+    let mut entries = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut entry = if line.starts_with('{') {
+            serde_json::from_str::<PromptEntry>(line).map_err(|e| {
+                CodedError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("Invalid JSON on line {} of `{display}`: {e} (batch.rs)", i + 1),
+                )
+            })?
+        } else {
+            PromptEntry {
+                prompt: line.to_string(),
+                ..Default::default()
+            }
+        };
+        entry.line = i + 1;
+        entries.push(entry);
+    }
+
+    debug!("Read {} prompts from {display}", entries.len());
+    Ok(entries)
+}
+
+/// A prompt that generated images
+#[derive(Debug, Serialize)]
+pub struct BatchSuccess {
+    pub line: usize,
+    pub files: Vec<String>,
+}
+
+/// A prompt that failed
+#[derive(Debug, Serialize)]
+pub struct BatchFailure {
+    pub line: usize,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Outcome of a prompts file run
+#[derive(Debug, Default, Serialize)]
+pub struct BatchSummary {
+    pub succeeded: Vec<BatchSuccess>,
+    pub failed: Vec<BatchFailure>,
+    /// Set when any prompt failed, so JSON output is a single document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorBody>,
+}
+
+impl BatchSummary {
+    pub fn total(&self) -> usize {
+        self.succeeded.len() + self.failed.len()
+    }
+}
+
+/// Generate and save the images for a single prompt
+async fn run_entry(
+    cli: Cli,
+    cache: Option<Cache>,
+) -> Result<Vec<String>> {
+    let start = Instant::now();
+    check_path_format(&cli.out, cli.get_format())?;
+    let request = cli.to_request()?;
+    let client = create_client(&request.service, &cli.timeout, cli.get_retry_policy())?;
+    check_ignored_parameters(client.as_ref(), &request, cli.strict)?;
+    let results = generate_batch(client.as_ref(), &request, cli.count, cache.as_ref()).await?;
+    let file_paths = write_images(&cli.out, &results, cli.get_format(), !cli.no_metadata, cli.no_clobber)?;
+    if cli.sidecar {
+        for (file_path, result) in file_paths.iter().zip(&results) {
+            write_sidecar(file_path, result, start.elapsed())?;
+        }
+    }
+    Ok(file_paths)
+}
+
+/// Run every prompt with at most `cli.concurrency` requests in flight
+pub async fn run_batch(
+    cli: &Cli,
+    entries: Vec<PromptEntry>,
+    cache: Option<Cache>,
+    pb: Option<ProgressBar>,
+) -> BatchSummary {
+    let total = entries.len();
+    let semaphore = Arc::new(Semaphore::new(cli.concurrency.into()));
+    let mut tasks = JoinSet::new();
+    // Panicked or aborted tasks only report their ID, so map it back to the line
+    let mut lines = HashMap::new();
+    let mut summary = BatchSummary::default();
+    // First line to use each output path, since concurrent lines would overwrite each other
+    let mut outputs: HashMap<String, usize> = HashMap::new();
+
+    for entry in entries {
+        let line = entry.line;
+        let cli = entry.apply(cli);
+        if let Some(first) = outputs.get(&cli.out) {
+            // Templates can still render differently (e.g., `{seed}`), but literal paths always collide
+            if !cli.out.contains('{') {
+                summary.failed.push(BatchFailure {
+                    line,
+                    code: ErrorCode::InvalidRequest,
+                    message: format!(
+                        "Output `{}` is already used by line {first}, add `{{line}}` to keep both (batch.rs)",
+                        cli.out
+                    ),
+                });
+                continue;
+            }
+            warn!(
+                "Output `{}` on line {line} is also used by line {first} and may overwrite it, add `{{line}}` to keep both",
+                cli.out
+            );
+        } else {
+            outputs.insert(cli.out.clone(), line);
+        }
+        let semaphore = Arc::clone(&semaphore);
+        let cache = cache.clone();
+        let handle = tasks.spawn(async move {
+            // The semaphore is never closed so acquiring can't fail
+            let _permit = semaphore.acquire_owned().await.unwrap();
+            debug!("Generating prompt on line {line}");
+            (line, run_entry(cli, cache).await)
+        });
+        lines.insert(handle.id(), line);
+    }
+
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((line, Ok(files))) => summary.succeeded.push(BatchSuccess { line, files }),
+            Ok((line, Err(e))) => {
+                debug!("Line {line} failed: {e}");
+                summary.failed.push(BatchFailure {
+                    line,
+                    code: error_code(&e),
+                    message: e.to_string(),
+                });
+            }
+            Err(e) => {
+                let line = lines.get(&e.id()).copied().unwrap_or_default();
+                warn!("Task for line {line} failed: {e}");
+                summary.failed.push(BatchFailure {
+                    line,
+                    code: ErrorCode::Internal,
+                    message: format!("Task failed: {e} (batch.rs)"),
+                });
+            }
+        }
+
+        if let Some(pb) = &pb {
+            pb.set_message(format!("Generating images ({}/{total})", summary.total()));
+        }
+    }
+
+    summary.succeeded.sort_by_key(|s| s.line);
+    summary.failed.sort_by_key(|f| f.line);
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use clap::Parser;
+
+    use super::*;
+
+    fn out(
+        template: &str,
+        entry: PromptEntry,
+    ) -> String {
+        let cli = Cli::try_parse_from(["txt2img", "-o", template, "a cat"]).unwrap();
+        entry.apply(&cli).out
+    }
+
+    fn read(
+        name: &str,
+        contents: &str,
+    ) -> Result<Vec<PromptEntry>> {
+        let path = env::temp_dir().join(format!("txt2img-{name}-{}.txt", process::id()));
+        fs::write(&path, contents).unwrap();
+        let entries = read_prompts_file(&path);
+        fs::remove_file(&path).unwrap();
+        entries
+    }
+
+    #[test]
+    fn reads_plain_lines() {
+        let entries = read("plain", "a cat\n  a dog  \n").unwrap();
+        let prompts: Vec<(usize, &str)> = entries.iter().map(|e| (e.line, e.prompt.as_str())).collect();
+        assert_eq!(prompts, [(1, "a cat"), (2, "a dog")]);
+        assert!(entries[0].model.is_none() && entries[0].seed.is_none());
+    }
+
+    #[test]
+    fn reads_json_lines() {
+        let entries = read(
+            "json",
+            r#"{"prompt": "a cat", "model": "flux", "seed": 42, "size": "512x768", "out": "cat.png"}"#,
+        )
+        .unwrap();
+        let entry = &entries[0];
+        assert_eq!(entry.prompt, "a cat");
+        assert_eq!(entry.model, Some(ModelId::from("flux")));
+        assert_eq!(entry.seed, Some(42));
+        assert_eq!(entry.size, Some(Size::new(512, 768)));
+        assert_eq!(entry.out.as_deref(), Some("cat.png"));
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let entries = read(
+            "comments",
+            "# header\n\na cat\n   # indented\n{\"prompt\": \"a dog\"}\n",
+        )
+        .unwrap();
+        let prompts: Vec<(usize, &str)> = entries.iter().map(|e| (e.line, e.prompt.as_str())).collect();
+        assert_eq!(prompts, [(3, "a cat"), (5, "a dog")]);
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        let e = read("invalid", "a cat\n{\"prompt\": \n").unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::InvalidRequest);
+        assert!(e.to_string().contains("line 2"), "{e}");
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let e = read("unknown", r#"{"prompt": "a cat", "colour": "red"}"#).unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::InvalidRequest);
+        assert!(e.to_string().contains("colour"), "{e}");
+    }
+
+    #[test]
+    fn numbers_outputs_by_line() {
+        let entry = || PromptEntry {
+            line: 7,
+            ..Default::default()
+        };
+        assert_eq!(out("out/cat.png", entry()), "out/cat-007.png");
+        assert_eq!(out("out/cat-{line}.png", entry()), "out/cat-007.png");
+        // The image number is left for write_images
+        assert_eq!(out("out/cat-{line}-{n}.png", entry()), "out/cat-007-{n}.png");
+        assert_eq!(out("out/cat-{n}.png", entry()), "out/cat-{n}-007.png");
+
+        let entry = PromptEntry {
+            out: Some("dog-{line}.png".to_string()),
+            ..entry()
+        };
+        assert_eq!(out("out/cat.png", entry), "dog-007.png");
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let path = env::temp_dir().join(format!("txt2img-missing-{}.txt", process::id()));
+        assert_eq!(error_code(&read_prompts_file(&path).unwrap_err()), ErrorCode::Io);
+    }
+}
@@ -0,0 +1,459 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::CacheConfig;
+use crate::error::{CodedError, ErrorCode};
+use crate::output::ImageFileFormat;
+use crate::request::GenerationRequest;
+use crate::result::GenerationResult;
+use crate::services::{OpenAIImageBackground, OpenAIImageQuality, OpenAIImageStyle, OpenAIModeration, ServiceId};
+
+/// Default size limit in MiB
+const DEFAULT_MAX_SIZE: u64 = 1024;
+
+/// FNV-1a, which is stable across builds unlike the standard library's hasher
+struct Fnv128(u128);
+
+impl Fnv128 {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    fn new() -> Self {
+        Self(Self::OFFSET)
+    }
+
+    fn write(
+        &mut self,
+        bytes: &[u8],
+    ) {
+        for byte in bytes {
+            self.0 ^= u128::from(*byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+}
+
+/// Everything that decides the image, resolved against the model defaults
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    service: &'a ServiceId,
+    /// Another server with the same service ID can return different images
+    base_url: &'a str,
+    /// Upstream model name, so aliases share entries
+    model: &'a str,
+    prompt: &'a str,
+    negative_prompt: Option<&'a str>,
+    width: Option<u16>,
+    height: Option<u16>,
+    steps: Option<u8>,
+    cfg: Option<f32>,
+    seed: u64,
+    style: Option<&'a OpenAIImageStyle>,
+    sampler: Option<&'a str>,
+    strength: Option<f32>,
+    quality: Option<OpenAIImageQuality>,
+    background: Option<OpenAIImageBackground>,
+    output_format: Option<ImageFileFormat>,
+    compression: Option<u8>,
+    moderation: Option<OpenAIModeration>,
+    /// Sorted so the key doesn't depend on insertion order
+    options: BTreeMap<String, Value>,
+}
+
+/// Manifest stored next to a cached image
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CacheEntry {
+    pub key: String,
+    /// RFC 3339 timestamp of when the image was cached
+    pub created_at: String,
+    pub service: ServiceId,
+    /// Upstream model name
+    pub model: String,
+    pub prompt: String,
+    pub seed: Option<u64>,
+    pub mime_type: String,
+    /// Size of the image in bytes
+    pub size: u64,
+    pub parameters: HashMap<String, Value>,
+    pub revised_prompt: Option<String>,
+    pub url: Option<String>,
+}
+
+/// Local cache of generated images for requests with a fixed seed
+#[derive(Clone, Debug)]
+pub struct Cache {
+    pub dir: PathBuf,
+    /// Size limit in bytes, with the least recently used entries evicted first
+    pub max_size: u64,
+}
+
+impl Cache {
+    /// Open the cache in `$XDG_CACHE_HOME/txt2img`
+    pub fn new(config: &CacheConfig) -> Result<Self> {
+        let dir = default_cache_dir().ok_or_else(|| {
+            CodedError::new(
+                ErrorCode::Config,
+                "Neither XDG_CACHE_HOME nor HOME is set for the cache (cache.rs)",
+            )
+        })?;
+        Ok(Self {
+            dir,
+            max_size: config.max_size.unwrap_or(DEFAULT_MAX_SIZE).saturating_mul(1024 * 1024),
+        })
+    }
+
+    /// Key for the request, or None without a seed since the image would differ every time
+    /// Models without a name (e.g., the loaded AUTOMATIC1111 checkpoint) can change between runs and aren't cached
+    pub fn key(
+        request: &GenerationRequest,
+        base_url: &str,
+    ) -> Result<Option<String>> {
+        let Some(seed) = request.seed else {
+            return Ok(None);
+        };
+        if request.model.name.is_empty() {
+            return Ok(None);
+        }
+        let key = CacheKey {
+            service: &request.service,
+            base_url,
+            model: &request.model.name,
+            prompt: &request.prompt,
+            negative_prompt: request.get_negative_prompt(),
+            width: request.get_width(),
+            height: request.get_height(),
+            steps: request.get_steps(),
+            cfg: request.get_cfg(),
+            seed,
+            style: request.get_style(),
+            sampler: request.get_sampler(),
+            strength: request.strength,
+            quality: request.quality,
+            background: request.background,
+            output_format: request.output_format,
+            compression: request.compression,
+            moderation: request.moderation,
+            options: request.get_options().into_iter().collect(),
+        };
+
+        let mut hasher = Fnv128::new();
+        hasher.write(&serde_json::to_vec(&key)?);
+
+        // Hash the input images and workflow by content so edited files don't hit stale entries
+        let workflow = request.model.workflow.as_ref().map(|w| &w.path);
+        for path in [request.init_image.as_ref(), request.mask.as_ref(), workflow]
+            .into_iter()
+            .flatten()
+        {
+            let bytes = fs::read(path).context(format!("Failed to read `{}` (cache.rs)", path.display()))?;
+            hasher.write(&(bytes.len() as u64).to_le_bytes());
+            hasher.write(&bytes);
+        }
+        Ok(Some(format!("{:032x}", hasher.0)))
+    }
+
+    fn manifest_path(
+        &self,
+        key: &str,
+    ) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    fn image_path(
+        &self,
+        key: &str,
+    ) -> PathBuf {
+        self.dir.join(format!("{key}.bin"))
+    }
+
+    /// Look up a cached image for the request
+    pub fn get(
+        &self,
+        key: &str,
+        request: &GenerationRequest,
+    ) -> Result<Option<GenerationResult>> {
+        let manifest = match fs::read_to_string(self.manifest_path(key)) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read cache entry (cache.rs)"),
+        };
+        let entry: CacheEntry = serde_json::from_str(&manifest).context("Invalid cache entry (cache.rs)")?;
+        let image_path = self.image_path(key);
+        let image = match fs::read(&image_path) {
+            Ok(image) => image,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read cached image (cache.rs)"),
+        };
+
+        // Mark the entry as recently used for eviction
+        if let Err(e) = File::options()
+            .write(true)
+            .open(&image_path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+        {
+            debug!("Failed to touch cache entry {key}: {e}");
+        }
+
+        let mut result = GenerationResult::new(
+            image,
+            Some(&entry.mime_type),
+            request,
+            &entry.parameters,
+            Duration::ZERO,
+        );
+        result.seed = entry.seed;
+        result.revised_prompt = entry.revised_prompt;
+        result.url = entry.url;
+        Ok(Some(result))
+    }
+
+    /// Store a generated image and evict old entries over the size limit
+    pub fn put(
+        &self,
+        key: &str,
+        result: &GenerationResult,
+    ) -> Result<()> {
+        fs::create_dir_all(&self.dir).context(format!(
+            "Failed to create cache directory `{}` (cache.rs)",
+            self.dir.display()
+        ))?;
+
+        let created_at: DateTime<Utc> = Utc::now();
+        let entry = CacheEntry {
+            key: key.to_string(),
+            created_at: created_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            service: result.request.service.clone(),
+            model: result.request.model.name.clone(),
+            prompt: result.request.prompt.clone(),
+            seed: result.seed,
+            mime_type: result.mime_type.clone(),
+            size: result.image.len() as u64,
+            parameters: result.parameters.clone(),
+            revised_prompt: result.revised_prompt.clone(),
+            url: result.url.clone(),
+        };
+
+        // Write the image first so a manifest never points at a missing image
+        fs::write(self.image_path(key), &result.image).context("Failed to write cached image (cache.rs)")?;
+        fs::write(self.manifest_path(key), serde_json::to_vec_pretty(&entry)?)
+            .context("Failed to write cache entry (cache.rs)")?;
+        debug!("Cached {key}");
+
+        self.evict()
+    }
+
+    /// Cached entries, most recently used first
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut entries: Vec<(SystemTime, CacheEntry)> = Vec::new();
+        for (key, used) in self.keys()? {
+            match fs::read_to_string(self.manifest_path(&key)) {
+                Ok(manifest) => match serde_json::from_str(&manifest) {
+                    Ok(entry) => entries.push((used, entry)),
+                    Err(e) => warn!("Skipping invalid cache entry {key}: {e}"),
+                },
+                Err(e) => debug!("Skipping cache entry {key}: {e}"),
+            }
+        }
+        entries.sort_by_key(|(used, _)| Reverse(*used));
+        Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Remove every entry and return how many were removed
+    pub fn clear(&self) -> Result<usize> {
+        let keys = self.keys()?;
+        for (key, _) in &keys {
+            self.remove(key)?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Keys of the cached images with when they were last used
+    fn keys(&self) -> Result<Vec<(String, SystemTime)>> {
+        let read_dir = match fs::read_dir(&self.dir) {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("Failed to read cache directory (cache.rs)"),
+        };
+
+        let mut keys = Vec::new();
+        for dir_entry in read_dir.flatten() {
+            let path = dir_entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("bin") {
+                continue;
+            }
+            let (Some(key), Ok(metadata)) = (path.file_stem().and_then(|s| s.to_str()), dir_entry.metadata()) else {
+                continue;
+            };
+            // Skip files that weren't written by the cache
+            if key.len() != 32 || !key.bytes().all(|b| b.is_ascii_hexdigit()) {
+                continue;
+            }
+            keys.push((key.to_string(), metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)));
+        }
+        Ok(keys)
+    }
+
+    /// Size of the cached images in bytes
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.sizes()?.iter().map(|(_, _, size)| size).sum())
+    }
+
+    fn sizes(&self) -> Result<Vec<(String, SystemTime, u64)>> {
+        let mut sizes = Vec::new();
+        for (key, used) in self.keys()? {
+            let size = [self.image_path(&key), self.manifest_path(&key)]
+                .iter()
+                .filter_map(|path| fs::metadata(path).ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            sizes.push((key, used, size));
+        }
+        Ok(sizes)
+    }
+
+    /// Remove the least recently used entries until the cache fits the size limit
+    fn evict(&self) -> Result<()> {
+        let mut sizes = self.sizes()?;
+        let mut total: u64 = sizes.iter().map(|(_, _, size)| size).sum();
+        sizes.sort_by_key(|(_, used, _)| *used);
+        for (key, _, size) in sizes {
+            if total <= self.max_size {
+                break;
+            }
+            debug!("Evicting cache entry {key}");
+            self.remove(&key)?;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    /// Remove an entry, ignoring files already removed by a concurrent run
+    fn remove(
+        &self,
+        key: &str,
+    ) -> Result<()> {
+        for path in [self.manifest_path(key), self.image_path(key)] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(e).context(format!("Failed to remove `{}` (cache.rs)", path.display()));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Get the default cache directory (`$XDG_CACHE_HOME/txt2img`)
+pub fn default_cache_dir() -> Option<PathBuf> {
+    let cache_home = env::var_os("XDG_CACHE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_home.join("txt2img"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+    use crate::services::ServiceId;
+
+    const BASE_URL: &str = "https://example.com";
+
+    fn request(seed: Option<u64>) -> GenerationRequest {
+        let mut builder = GenerationRequest::builder("a cat").steps(4);
+        if let Some(seed) = seed {
+            builder = builder.seed(seed);
+        }
+        builder.build().unwrap()
+    }
+
+    fn key(request: &GenerationRequest) -> String {
+        Cache::key(request, BASE_URL).unwrap().unwrap()
+    }
+
+    #[test]
+    fn hashes_fnv1a_test_vectors() {
+        let mut hasher = Fnv128::new();
+        assert_eq!(hasher.0, 0x6c62272e07bb014262b821756295c58d);
+        hasher.write(b"a");
+        assert_eq!(hasher.0, 0xd228cb696f1a8caf78912b704e4a8964);
+    }
+
+    #[test]
+    fn keys_are_stable() {
+        let request = request(Some(42));
+        let key = key(&request);
+        assert_eq!(key.len(), 32);
+        assert!(key.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(key, self::key(&request));
+        assert_eq!(key, self::key(&request.clone()));
+        // Changing the key invalidates existing caches, so only update this on purpose
+        assert_eq!(key, "d01a4b108500e6335d2e6ebe12e8e245");
+    }
+
+    #[test]
+    fn keys_differ_by_parameters() {
+        let key = key(&request(Some(42)));
+        assert_ne!(key, self::key(&request(Some(43))));
+
+        let mut prompt = request(Some(42));
+        prompt.prompt = "a dog".to_string();
+        assert_ne!(key, self::key(&prompt));
+
+        assert_ne!(
+            key,
+            Cache::key(&request(Some(42)), "http://127.0.0.1:8000")
+                .unwrap()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn skips_requests_without_seed() {
+        assert_eq!(Cache::key(&request(None), BASE_URL).unwrap(), None);
+    }
+
+    #[test]
+    fn skips_unnamed_models() {
+        let request = GenerationRequest::builder("a cat")
+            .service(ServiceId::from("a1111"))
+            .model("current")
+            .seed(42)
+            .build()
+            .unwrap();
+        assert_eq!(Cache::key(&request, BASE_URL).unwrap(), None);
+    }
+
+    #[test]
+    fn hashes_init_image_contents() {
+        let path = env::temp_dir().join(format!("txt2img-cache-test-{}.png", process::id()));
+        fs::write(&path, b"first").unwrap();
+        let request = GenerationRequest::builder("a cat")
+            .seed(42)
+            .init_image(&path)
+            .build()
+            .unwrap();
+        let first = key(&request);
+
+        fs::write(&path, b"second").unwrap();
+        let second = key(&request);
+        fs::remove_file(&path).unwrap();
+
+        assert_ne!(first, second);
+        assert!(Cache::key(&request, BASE_URL).is_err());
+    }
+}
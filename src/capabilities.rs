@@ -0,0 +1,424 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CodedError, ErrorCode};
+use crate::request::GenerationRequest;
+use crate::services::OpenAIImageStyle;
+
+/// Image size written as `WxH` (e.g., `1024x1024`)
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Size {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Size {
+    pub const fn new(
+        width: u16,
+        height: u16,
+    ) -> Self {
+        Self { width, height }
+    }
+
+    /// Distance between sizes on a log scale, weighting the aspect ratio over the area
+    fn distance(
+        &self,
+        other: &Size,
+    ) -> f64 {
+        let width = (f64::from(self.width) / f64::from(other.width)).ln();
+        let height = (f64::from(self.height) / f64::from(other.height)).ln();
+        (width - height).abs() + (width + height).abs() / 4.0
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl FromStr for Size {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parsed = s
+            .split_once(['x', 'X'])
+            .and_then(|(width, height)| Some((width.trim().parse().ok()?, height.trim().parse().ok()?)));
+        match parsed {
+            Some((width, height)) if width > 0 && height > 0 => Ok(Self { width, height }),
+            _ => bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!("Invalid size `{s}`, expected `WxH` (e.g., `1024x768`) (capabilities.rs)")
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Size {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Size> for String {
+    fn from(size: Size) -> Self {
+        size.to_string()
+    }
+}
+
+/// Aspect ratio written as `W:H` (e.g., `16:9`) or a preset name
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AspectRatio {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl AspectRatio {
+    /// Named presets for common ratios
+    pub const PRESETS: [(&'static str, AspectRatio); 4] = [
+        ("square", AspectRatio::new(1, 1)),
+        ("portrait", AspectRatio::new(2, 3)),
+        ("landscape", AspectRatio::new(3, 2)),
+        ("wide", AspectRatio::new(16, 9)),
+    ];
+
+    pub const fn new(
+        width: u16,
+        height: u16,
+    ) -> Self {
+        Self { width, height }
+    }
+
+    /// Size with this ratio and about the same area as `base`, before snapping to the model
+    fn size_with_area_of(
+        &self,
+        base: Size,
+    ) -> Size {
+        let area = f64::from(base.width) * f64::from(base.height);
+        let ratio = f64::from(self.width) / f64::from(self.height);
+        let width = (area * ratio).sqrt();
+        let height = width / ratio;
+        Size::new(width.round() as u16, height.round() as u16)
+    }
+}
+
+impl fmt::Display for AspectRatio {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "{}:{}", self.width, self.height)
+    }
+}
+
+impl FromStr for AspectRatio {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some((_, preset)) = Self::PRESETS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s.trim()))
+        {
+            return Ok(*preset);
+        }
+        let parsed = s
+            .split_once(':')
+            .and_then(|(width, height)| Some((width.trim().parse().ok()?, height.trim().parse().ok()?)));
+        match parsed {
+            Some((width, height)) if width > 0 && height > 0 => Ok(Self { width, height }),
+            _ => {
+                let presets: Vec<&str> = Self::PRESETS.iter().map(|(name, _)| *name).collect();
+                bail!(CodedError::new(
+                    ErrorCode::InvalidRequest,
+                    format!(
+                        "Invalid aspect ratio `{s}`, expected `W:H` (e.g., `16:9`) or one of {} (capabilities.rs)",
+                        presets.join(", ")
+                    )
+                ))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for AspectRatio {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<AspectRatio> for String {
+    fn from(aspect: AspectRatio) -> Self {
+        aspect.to_string()
+    }
+}
+
+fn default_seed() -> bool {
+    true
+}
+
+/// What a model accepts, checked before a request is sent
+/// Unset limits aren't checked, so ad-hoc models accept anything
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Capabilities {
+    /// Exact sizes the model accepts (e.g., DALL-E), checked instead of the size limits
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sizes: Vec<Size>,
+    pub min_size: Option<u16>,
+    pub max_size: Option<u16>,
+    /// Width and height must be a multiple of this (e.g., 8 for SDXL)
+    pub size_multiple: Option<u16>,
+    pub min_steps: Option<u8>,
+    pub max_steps: Option<u8>,
+    pub min_cfg: Option<f32>,
+    pub max_cfg: Option<f32>,
+    /// Whether the provider honors a seed
+    #[serde(default = "default_seed")]
+    pub seed: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub styles: Vec<OpenAIImageStyle>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            sizes: Vec::new(),
+            min_size: None,
+            max_size: None,
+            size_multiple: None,
+            min_steps: None,
+            max_steps: None,
+            min_cfg: None,
+            max_cfg: None,
+            seed: true,
+            styles: Vec::new(),
+        }
+    }
+}
+
+/// Error for a parameter the model doesn't accept
+fn invalid(message: String) -> anyhow::Error {
+    CodedError::new(ErrorCode::InvalidRequest, format!("{message} (capabilities.rs)")).into()
+}
+
+impl Capabilities {
+    /// Round a dimension to the nearest multiple within the limits
+    fn snap_dimension(
+        &self,
+        value: u16,
+    ) -> u16 {
+        let multiple = self.size_multiple.unwrap_or(1).max(1);
+        let min = self.min_size.unwrap_or(multiple).div_ceil(multiple) * multiple;
+        let max = self.max_size.map_or(u16::MAX, |max| max / multiple * multiple).max(min);
+        let rounded = (u32::from(value) + u32::from(multiple) / 2) / u32::from(multiple) * u32::from(multiple);
+        u16::try_from(rounded).unwrap_or(u16::MAX).clamp(min, max)
+    }
+
+    /// Closest allowed size for the requested one
+    pub fn closest_size(
+        &self,
+        size: Size,
+    ) -> Size {
+        if self.sizes.is_empty() {
+            return Size::new(self.snap_dimension(size.width), self.snap_dimension(size.height));
+        }
+        self.sizes
+            .iter()
+            .copied()
+            .min_by(|a, b| a.distance(&size).total_cmp(&b.distance(&size)))
+            .unwrap_or(size)
+    }
+
+    /// Closest allowed size for the aspect ratio, keeping about the area of the default size
+    pub fn size_for_aspect(
+        &self,
+        aspect: AspectRatio,
+        default: Size,
+    ) -> Size {
+        self.closest_size(aspect.size_with_area_of(default))
+    }
+
+    /// Check a dimension against the limits
+    fn check_dimension(
+        &self,
+        name: &str,
+        value: u16,
+        model: &str,
+    ) -> Result<()> {
+        let suggestion = self.snap_dimension(value);
+        if let Some(min) = self.min_size.filter(|min| value < *min) {
+            return Err(invalid(format!(
+                "{name} {value} is below the minimum of {min} for `{model}`, try {suggestion}"
+            )));
+        }
+        if let Some(max) = self.max_size.filter(|max| value > *max) {
+            return Err(invalid(format!(
+                "{name} {value} is above the maximum of {max} for `{model}`, try {suggestion}"
+            )));
+        }
+        if let Some(multiple) = self
+            .size_multiple
+            .filter(|multiple| *multiple > 1 && !value.is_multiple_of(*multiple))
+        {
+            return Err(invalid(format!(
+                "{name} {value} isn't a multiple of {multiple} for `{model}`, try {suggestion}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check the resolved request parameters, suggesting valid values
+    pub fn check(
+        &self,
+        request: &GenerationRequest,
+    ) -> Result<()> {
+        let model = request.model.id.0.as_str();
+
+        match (request.get_width(), request.get_height()) {
+            (Some(width), Some(height)) if !self.sizes.is_empty() => {
+                let size = Size::new(width, height);
+                if !self.sizes.contains(&size) {
+                    let sizes: Vec<String> = self.sizes.iter().map(Size::to_string).collect();
+                    return Err(invalid(format!(
+                        "Size {size} isn't supported by `{model}`, use one of {} (closest is {})",
+                        sizes.join(", "),
+                        self.closest_size(size)
+                    )));
+                }
+            }
+            (width, height) => {
+                if let Some(width) = width {
+                    self.check_dimension("Width", width, model)?;
+                }
+                if let Some(height) = height {
+                    self.check_dimension("Height", height, model)?;
+                }
+            }
+        }
+
+        if let Some(steps) = request.get_steps() {
+            if let Some(min) = self.min_steps.filter(|min| steps < *min) {
+                return Err(invalid(format!(
+                    "Steps {steps} is below the minimum of {min} for `{model}`"
+                )));
+            }
+            if let Some(max) = self.max_steps.filter(|max| steps > *max) {
+                return Err(invalid(format!(
+                    "Steps {steps} is above the maximum of {max} for `{model}`"
+                )));
+            }
+        }
+
+        if let Some(cfg) = request.get_cfg() {
+            if let Some(min) = self.min_cfg.filter(|min| cfg < *min) {
+                return Err(invalid(format!(
+                    "CFG {cfg} is below the minimum of {min} for `{model}`"
+                )));
+            }
+            if let Some(max) = self.max_cfg.filter(|max| cfg > *max) {
+                return Err(invalid(format!(
+                    "CFG {cfg} is above the maximum of {max} for `{model}`"
+                )));
+            }
+        }
+
+        // Styles are only sent to models that list them
+        if let Some(style) = request.get_style().filter(|_| !self.styles.is_empty()) {
+            if !self.styles.contains(style) {
+                let styles: Vec<String> = self.styles.iter().map(|s| s.to_string()).collect();
+                return Err(invalid(format!(
+                    "Style `{style}` isn't supported by `{model}`, use one of {}",
+                    styles.join(", ")
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::error_code;
+
+    fn sdxl() -> Capabilities {
+        Capabilities {
+            min_size: Some(256),
+            max_size: Some(1440),
+            size_multiple: Some(64),
+            ..Default::default()
+        }
+    }
+
+    fn dalle() -> Capabilities {
+        Capabilities {
+            sizes: vec![Size::new(1024, 1024), Size::new(1792, 1024), Size::new(1024, 1792)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!("1024x768".parse::<Size>().unwrap(), Size::new(1024, 768));
+        assert_eq!(" 512 X 512 ".parse::<Size>().unwrap(), Size::new(512, 512));
+        for invalid in ["1024", "0x512", "axb", "70000x1"] {
+            let e = invalid.parse::<Size>().unwrap_err();
+            assert_eq!(error_code(&e), ErrorCode::InvalidRequest, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn parses_aspect_ratios() {
+        assert_eq!("16:9".parse::<AspectRatio>().unwrap(), AspectRatio::new(16, 9));
+        assert_eq!("Portrait".parse::<AspectRatio>().unwrap(), AspectRatio::new(2, 3));
+        for invalid in ["16x9", "0:1", "tall"] {
+            let e = invalid.parse::<AspectRatio>().unwrap_err();
+            assert_eq!(error_code(&e), ErrorCode::InvalidRequest, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn snaps_to_multiple_within_limits() {
+        let capabilities = sdxl();
+        assert_eq!(capabilities.closest_size(Size::new(1000, 700)), Size::new(1024, 704));
+        assert_eq!(capabilities.closest_size(Size::new(100, 4000)), Size::new(256, 1408));
+    }
+
+    #[test]
+    fn keeps_sizes_without_limits() {
+        let size = Size::new(1001, 333);
+        assert_eq!(Capabilities::default().closest_size(size), size);
+    }
+
+    #[test]
+    fn snaps_to_closest_listed_size() {
+        let capabilities = dalle();
+        assert_eq!(capabilities.closest_size(Size::new(1920, 1080)), Size::new(1792, 1024));
+        assert_eq!(capabilities.closest_size(Size::new(512, 512)), Size::new(1024, 1024));
+        assert_eq!(capabilities.closest_size(Size::new(600, 1000)), Size::new(1024, 1792));
+    }
+
+    #[test]
+    fn sizes_aspect_ratios_by_area() {
+        let default = Size::new(1024, 1024);
+        let wide = sdxl().size_for_aspect(AspectRatio::new(16, 9), default);
+        assert_eq!(wide, Size::new(1344, 768));
+        assert_eq!(sdxl().size_for_aspect(AspectRatio::new(1, 1), default), default);
+        assert_eq!(
+            dalle().size_for_aspect(AspectRatio::new(2, 3), default),
+            Size::new(1024, 1792)
+        );
+    }
+}
@@ -1,56 +1,103 @@
+use std::path::PathBuf;
 use std::sync::LazyLock;
+use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
-use clap::{ArgAction, ArgGroup, Parser};
+use anyhow::Result;
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand};
 use colored::Colorize;
-use strum::VariantNames;
+use image::ImageFormat;
 
-use crate::services::{get_or_init_services, Model, ModelId, OpenAIImageStyle, ServiceId};
+use crate::capabilities::{AspectRatio, Size};
+use crate::client::RetryPolicy;
+use crate::error::{CodedError, ErrorCode};
+use crate::output::{ImageFileFormat, OutputFormat};
+use crate::request::GenerationRequest;
+use crate::services::{
+    get_or_init_services, Model, ModelId, OpenAIImageBackground, OpenAIImageQuality, OpenAIImageStyle,
+    OpenAIModeration, Service, ServiceId,
+};
+use crate::utils::STDOUT;
 
 const PARAMETERS: &str = "Parameters";
 
 // Lazy initialization so we can style the text with Colorize instead of hard-coding ANSI codes
 pub static AFTER_HELP: LazyLock<String> = LazyLock::new(|| {
     format!(
-        "{}\n  {}\n  {}\n  {}",
+        "{}\n  {}\n  {}\n  {}\n  {}\n  {}\n  {}\n  {}",
         "Environment Variables:".bold().underline(),
         "HF_TOKEN                 Required for Hugging Face",
         "OPENAI_API_KEY           Required for OpenAI",
         "TOGETHER_API_KEY         Required for Together.ai",
+        "HF_BASE_URL              Hugging Face API base URL",
+        "OPENAI_BASE_URL          OpenAI API base URL",
+        "TOGETHER_BASE_URL        Together.ai API base URL",
+        "A1111_BASE_URL           AUTOMATIC1111 API base URL",
     )
 });
 
 /// Command line interface
-#[derive(Parser, Debug)]
+#[derive(Clone, Debug, Parser)]
 #[command(
     name = "txt2img",
     version,
     about = "Text-to-image generation with cloud models.",
     after_help = AFTER_HELP.as_str(), // same as `&*`
+    subcommand_negates_reqs = true,
 )]
 #[command(group(
     ArgGroup::new("parameters")
-        .args(["negative_prompt", "width", "height", "cfg", "steps", "seed", "style", "out"])
+        .args(["negative_prompt", "width", "height", "size", "aspect", "cfg", "steps", "seed", "style", "quality", "background", "compression", "moderation", "sampler", "init_image", "strength", "mask", "count", "no_metadata", "sidecar", "format", "out", "no_clobber"])
         .multiple(true)
         .required(false),
 ))]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// The text to guide the generation (required)
-    #[arg(required_unless_present_any = ["help", "list_models", "list_services", "version"])]
+    #[arg(required_unless_present_any = ["help", "list_models", "list_services", "prompts_file", "version"])]
     pub prompt: Option<String>,
 
-    /// Model to use
-    #[arg(short, long, hide_possible_values = true)]
+    /// Model alias or Hugging Face repo ID
+    #[arg(short, long)]
     pub model: Option<ModelId>,
 
-    /// Service to use
-    #[arg(short, long, hide_possible_values = true)]
+    /// Service to use (see `--list-services`)
+    #[arg(short, long)]
     pub service: Option<ServiceId>,
 
+    /// Config file path
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Read prompts from a text or JSONL file (one per line)
+    #[arg(long, conflicts_with = "prompt")]
+    pub prompts_file: Option<PathBuf>,
+
+    /// Maximum concurrent requests with --prompts-file
+    #[arg(
+        long,
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u8).range(1..),
+    )]
+    pub concurrency: u8,
+
     /// Timeout in seconds
     #[arg(short, long, default_value_t = 60)] // use default_value_t for numeric or other types
     pub timeout: u64, // passed to Duration::from_secs
 
+    /// Retries for rate limits, gateway errors, and timeouts
+    #[arg(long, default_value_t = 3)]
+    pub retries: u8,
+
+    /// Longest wait in seconds between retries
+    #[arg(long, default_value_t = 60)]
+    pub retry_max_wait: u64,
+
+    /// Skip the result cache for this run
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub no_cache: bool,
+
     /// Suppress progress
     #[arg(short, long, action = ArgAction::SetTrue, conflicts_with = "debug")]
     pub quiet: bool,
@@ -59,6 +106,14 @@ pub struct Cli {
     #[arg(long, action = ArgAction::SetTrue, conflicts_with = "quiet")]
     pub debug: bool,
 
+    /// Display the image in the terminal (kitty, iTerm2, sixel, or half-blocks)
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub preview: bool,
+
+    /// Output format for results and errors
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    pub output: OutputFormat,
+
     /// Print models
     #[arg(long, action = ArgAction::SetTrue, conflicts_with = "list_services")]
     pub list_models: bool,
@@ -91,20 +146,80 @@ pub struct Cli {
     #[arg(long, help_heading = PARAMETERS)]
     pub height: Option<u16>,
 
+    /// Size as `WxH`, snapped to the closest size the model supports
+    #[arg(long, conflicts_with_all = ["width", "height", "aspect"], help_heading = PARAMETERS)]
+    pub size: Option<Size>,
+
+    /// Aspect ratio as `W:H` or square, portrait, landscape, or wide
+    #[arg(long, conflicts_with_all = ["width", "height"], help_heading = PARAMETERS)]
+    pub aspect: Option<AspectRatio>,
+
     /// Seed for reproducibility
     #[arg(long, help_heading = PARAMETERS)]
     pub seed: Option<u64>,
 
-    /// Image style (OpenAI only)
+    /// Image style (DALL-E 3 only, vivid by default)
+    #[arg(long, value_enum, help_heading = PARAMETERS)]
+    pub style: Option<OpenAIImageStyle>,
+
+    /// Image quality (OpenAI only)
+    #[arg(long, value_enum, help_heading = PARAMETERS)]
+    pub quality: Option<OpenAIImageQuality>,
+
+    /// Image background (GPT Image only)
+    #[arg(long, value_enum, help_heading = PARAMETERS)]
+    pub background: Option<OpenAIImageBackground>,
+
+    /// Compression level for JPEG and WebP, from 0 to 100 (GPT Image only)
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        help_heading = PARAMETERS
+    )]
+    pub compression: Option<u8>,
+
+    /// Moderation level (GPT Image only)
+    #[arg(long, value_enum, help_heading = PARAMETERS)]
+    pub moderation: Option<OpenAIModeration>,
+
+    /// Sampler name (AUTOMATIC1111 only)
+    #[arg(long, help_heading = PARAMETERS)]
+    pub sampler: Option<String>,
+
+    /// Start from an image instead of noise (image-to-image)
+    #[arg(long, help_heading = PARAMETERS)]
+    pub init_image: Option<PathBuf>,
+
+    /// How much to change the init image, from 0 (keep) to 1 (replace)
+    #[arg(long, help_heading = PARAMETERS)]
+    pub strength: Option<f32>,
+
+    /// Inpaint the transparent areas of this PNG (requires --init-image)
+    #[arg(long, help_heading = PARAMETERS)]
+    pub mask: Option<PathBuf>,
+
+    /// Number of images to generate
     #[arg(
         long,
-        value_enum,
-        default_value = "vivid",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(1..),
         help_heading = PARAMETERS
     )]
-    pub style: OpenAIImageStyle,
+    pub count: u8,
+
+    /// Don't embed generation parameters in the image
+    #[arg(long, action = ArgAction::SetTrue, help_heading = PARAMETERS)]
+    pub no_metadata: bool,
 
-    /// Output file path
+    /// Write a JSON manifest next to each image
+    #[arg(long, action = ArgAction::SetTrue, help_heading = PARAMETERS)]
+    pub sidecar: bool,
+
+    /// Image format, also requested from GPT Image (inferred from the output extension by default)
+    #[arg(long, value_enum, help_heading = PARAMETERS)]
+    pub format: Option<ImageFileFormat>,
+
+    /// Output file path or template, e.g. `out/{date}/{model}-{seed}.{ext}` (`-` for stdout)
     #[arg(
         short,
         long,
@@ -112,13 +227,58 @@ pub struct Cli {
         help_heading = PARAMETERS
     )] // use default_value for strings
     pub out: String,
+
+    /// Append `-1`, `-2`, etc. instead of overwriting existing files
+    #[arg(long, action = ArgAction::SetTrue, help_heading = PARAMETERS)]
+    pub no_clobber: bool,
+
+    /// Error instead of warning when the model ignores a parameter
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub strict: bool,
+}
+
+/// Subcommands
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// Print the generation parameters embedded in images
+    Inspect(InspectArgs),
+    /// Manage the local result cache
+    Cache(CacheArgs),
+}
+
+/// Arguments for the inspect subcommand
+#[derive(Args, Clone, Debug)]
+pub struct InspectArgs {
+    /// Image files to inspect
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Regenerate each image with the same settings (saved as `<name>-rerun.<ext>`)
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub rerun: bool,
+}
+
+/// Arguments for the cache subcommand
+#[derive(Args, Clone, Debug)]
+pub struct CacheArgs {
+    #[command(subcommand)]
+    pub command: CacheCommand,
+}
+
+/// Cache subcommands
+#[derive(Clone, Debug, Subcommand)]
+pub enum CacheCommand {
+    /// List cached images, most recently used first
+    Ls,
+    /// Remove every cached image
+    Clear,
 }
 
 // https://docs.rs/clap/latest/clap/struct.Arg.html#implementations
 impl Cli {
     /// Get the services
-    pub fn get_services(&self) -> Result<&'static [&'static str]> {
-        Ok(ServiceId::VARIANTS)
+    pub fn get_services(&self) -> Result<Vec<&'static ServiceId>> {
+        Ok(get_or_init_services().iter().map(|s| &s.id).collect())
     }
 
     /// Get the service
@@ -131,71 +291,105 @@ impl Cli {
         Ok(&services.default.id)
     }
 
+    /// Get the current service configuration
+    pub fn get_service_config(&self) -> Result<&'static Service> {
+        get_or_init_services().get(self.get_service()?)
+    }
+
     /// Get the models for the current service
     pub fn get_models(&self) -> Result<&Vec<Model>> {
-        let services = get_or_init_services();
-        match self.get_service()? {
-            ServiceId::Hf => Ok(&services.hf.models),
-            ServiceId::Openai => Ok(&services.openai.models),
-            ServiceId::Together => Ok(&services.together.models),
-        }
+        Ok(&self.get_service_config()?.models)
     }
 
     /// Get the model config for the current service
-    pub fn get_model(&self) -> Result<&Model> {
-        let model_id = if let Some(model) = &self.model {
-            model
-        } else {
-            let services = get_or_init_services();
-            match self.get_service()? {
-                ServiceId::Hf => &services.hf.default.id,
-                ServiceId::Openai => &services.openai.default.id,
-                ServiceId::Together => &services.together.default.id,
-            }
-        };
-        let model = self
-            .get_models()?
-            .iter()
-            .find(|m| m.id == *model_id) // deref to compare values not references
-            .context(format!("Unsupported model `{model_id}` (cli.rs)"))?;
-        Ok(model)
+    pub fn get_model(&self) -> Result<Model> {
+        self.get_service_config()?.resolve_model(self.model.as_ref())
     }
 
-    /// Get the negative prompt or None
-    pub fn get_negative_prompt(&self) -> Result<Option<&str>> {
-        let model = self.get_model()?;
-        Ok(self.negative_prompt.as_deref().or(model.negative_prompt.as_deref()))
+    /// Get the image format for writing
+    pub fn get_format(&self) -> Option<ImageFormat> {
+        self.format.map(ImageFormat::from)
     }
 
-    /// Get the number of steps
-    pub fn get_steps(&self) -> Result<u8> {
-        let model = self.get_model()?;
-        self.steps
-            .or(model.steps)
-            .ok_or_else(|| anyhow!("Model `{}` does not support steps (cli.rs)", model.id))
+    /// Whether stdout is reserved for output, so logs must go to stderr
+    pub fn is_stdout_reserved(&self) -> bool {
+        self.out == STDOUT || self.output == OutputFormat::Json
     }
 
-    /// Get the guidance scale
-    pub fn get_cfg(&self) -> Result<f32> {
-        let model = self.get_model()?;
-        self.cfg
-            .or(model.cfg)
-            .ok_or_else(|| anyhow!("Model `{}` does not support cfg (cli.rs)", model.id))
+    /// Get the retry policy for clients
+    pub fn get_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            max_wait: Duration::from_secs(self.retry_max_wait),
+        }
     }
 
-    /// Get the width
-    pub fn get_width(&self) -> Result<u16> {
-        let model = self.get_model()?;
-        self.width
-            .or(model.width)
-            .ok_or_else(|| anyhow!("Model `{}` does not support width (cli.rs)", model.id))
-    }
+    /// Convert the arguments into a generation request
+    pub fn to_request(&self) -> Result<GenerationRequest> {
+        let prompt = self
+            .prompt
+            .as_deref()
+            .ok_or_else(|| CodedError::new(ErrorCode::InvalidRequest, "Prompt is required (cli.rs)"))?;
+        let mut builder = GenerationRequest::builder(prompt).service(self.get_service()?.clone());
+
+        if let Some(model) = &self.model {
+            builder = builder.model(model.clone());
+        }
+        if let Some(style) = &self.style {
+            builder = builder.style(style.clone());
+        }
+        if let Some(negative_prompt) = &self.negative_prompt {
+            builder = builder.negative_prompt(negative_prompt);
+        }
+        if let Some(width) = self.width {
+            builder = builder.width(width);
+        }
+        if let Some(height) = self.height {
+            builder = builder.height(height);
+        }
+        if let Some(size) = self.size {
+            builder = builder.size(size);
+        }
+        if let Some(aspect) = self.aspect {
+            builder = builder.aspect(aspect);
+        }
+        if let Some(steps) = self.steps {
+            builder = builder.steps(steps);
+        }
+        if let Some(cfg) = self.cfg {
+            builder = builder.cfg(cfg);
+        }
+        if let Some(seed) = self.seed {
+            builder = builder.seed(seed);
+        }
+        if let Some(sampler) = &self.sampler {
+            builder = builder.sampler(sampler);
+        }
+        if let Some(init_image) = &self.init_image {
+            builder = builder.init_image(init_image);
+        }
+        if let Some(strength) = self.strength {
+            builder = builder.strength(strength);
+        }
+        if let Some(mask) = &self.mask {
+            builder = builder.mask(mask);
+        }
+        if let Some(quality) = self.quality {
+            builder = builder.quality(quality);
+        }
+        if let Some(background) = self.background {
+            builder = builder.background(background);
+        }
+        if let Some(format) = self.format {
+            builder = builder.output_format(format);
+        }
+        if let Some(compression) = self.compression {
+            builder = builder.compression(compression);
+        }
+        if let Some(moderation) = self.moderation {
+            builder = builder.moderation(moderation);
+        }
 
-    /// Get the height
-    pub fn get_height(&self) -> Result<u16> {
-        let model = self.get_model()?;
-        self.height
-            .or(model.height)
-            .ok_or_else(|| anyhow!("Model `{}` does not support height (cli.rs)", model.id))
+        builder.build()
     }
 }
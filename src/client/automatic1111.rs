@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::debug;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use serde_json::json;
+
+use crate::error::{CodedError, ErrorCode};
+use crate::request::GenerationRequest;
+use crate::result::GenerationResult;
+use crate::services::{Model, Service, ServiceKind};
+
+use super::retry::{send_with_retry, RetryPolicy};
+use super::Client;
+
+const DEFAULT_URL: &str = "http://127.0.0.1:7860";
+
+/// Response from the txt2img endpoint
+#[derive(serde::Deserialize, Debug)]
+struct Automatic1111Response {
+    images: Vec<String>,
+    /// JSON-encoded generation info
+    #[serde(default)]
+    info: Option<String>,
+}
+
+/// Generation info with the seeds that were used
+#[derive(serde::Deserialize, Debug, Default)]
+struct Automatic1111Info {
+    seed: Option<u64>,
+    #[serde(default)]
+    all_seeds: Vec<u64>,
+}
+
+/// Error response from the WebUI (FastAPI uses `detail`, the WebUI uses `error`)
+#[derive(serde::Deserialize, Debug)]
+struct Automatic1111ErrorResponse {
+    detail: Option<serde_json::Value>,
+    error: Option<String>,
+}
+
+/// AUTOMATIC1111 Stable Diffusion WebUI client (started with `--api`)
+#[derive(Debug)]
+pub struct Automatic1111Client {
+    pub client: reqwest::Client,
+    pub base_url: String,
+    pub timeout: u64,
+    pub retry: RetryPolicy,
+}
+
+pub(super) fn supported_parameters(_model: &Model) -> Vec<&'static str> {
+    vec!["negative_prompt", "width", "height", "steps", "cfg", "seed", "sampler"]
+}
+
+#[async_trait::async_trait]
+impl Client for Automatic1111Client {
+    fn new(
+        service: &Service,
+        timeout: u64,
+        retry: RetryPolicy,
+    ) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+
+        // https://github.com/AUTOMATIC1111/stable-diffusion-webui/wiki/API
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        debug!("Creating AUTOMATIC1111 client for `{}`", service.id);
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(timeout))
+            .build()
+            .context("System network error (automatic1111.rs)")?;
+
+        let base_url = service.get_base_url(DEFAULT_URL)?;
+        debug!("Using base URL {base_url}");
+
+        Ok(Self {
+            client,
+            base_url,
+            timeout,
+            retry,
+        })
+    }
+
+    /// Images are generated sequentially on one GPU, so keep batches small
+    fn max_batch_size(
+        &self,
+        _model: &Model,
+    ) -> u8 {
+        4
+    }
+
+    /// Any checkpoint can be used for image-to-image
+    fn supports_init_image(
+        &self,
+        _model: &Model,
+    ) -> bool {
+        true
+    }
+
+    fn supports_strength(
+        &self,
+        _model: &Model,
+    ) -> bool {
+        true
+    }
+
+    fn supports_mask(
+        &self,
+        _model: &Model,
+    ) -> bool {
+        true
+    }
+
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn kind(&self) -> ServiceKind {
+        ServiceKind::Automatic1111
+    }
+
+    /// Generate images using the WebUI API
+    async fn generate_images(
+        &self,
+        request: &GenerationRequest,
+        n: u8,
+    ) -> Result<Vec<GenerationResult>> {
+        let start = Instant::now();
+        let model = &request.model;
+        let mut request_body = HashMap::new();
+
+        request_body.insert("prompt".to_string(), json!(request.prompt));
+        request_body.insert("batch_size".to_string(), json!(n));
+
+        // The WebUI picks a random seed for -1
+        let seed = match request.seed {
+            Some(seed) => json!(seed),
+            None => json!(-1),
+        };
+        request_body.insert("seed".to_string(), seed);
+
+        if let Some(negative_prompt) = request.get_negative_prompt() {
+            request_body.insert("negative_prompt".to_string(), json!(negative_prompt));
+        }
+
+        if let Some(steps) = request.get_steps() {
+            request_body.insert("steps".to_string(), json!(steps));
+        }
+
+        if let Some(cfg) = request.get_cfg() {
+            request_body.insert("cfg_scale".to_string(), json!(cfg));
+        }
+
+        if let Some(width) = request.get_width() {
+            request_body.insert("width".to_string(), json!(width));
+        }
+
+        if let Some(height) = request.get_height() {
+            request_body.insert("height".to_string(), json!(height));
+        }
+
+        if let Some(sampler) = request.get_sampler() {
+            request_body.insert("sampler_name".to_string(), json!(sampler));
+        }
+
+        if let Some(strength) = request.strength {
+            request_body.insert("denoising_strength".to_string(), json!(strength));
+        }
+
+        // Switch checkpoints unless the model uses the loaded one
+        if !model.name.is_empty() {
+            request_body.insert(
+                "override_settings".to_string(),
+                json!({ "sd_model_checkpoint": model.name }),
+            );
+        }
+
+        // Add options if present
+        for (key, value) in request.get_options() {
+            request_body.insert(key, value);
+        }
+
+        // Image-to-image has its own endpoint
+        // The encoded image is kept out of the recorded parameters so sidecars stay small
+        let mut body = request_body.clone();
+        let endpoint = match request.read_init_image()? {
+            Some(image) => {
+                if let Some(mask) = request.read_mask(&image)? {
+                    body.insert("mask".to_string(), json!(STANDARD.encode(mask.to_grayscale_png()?)));
+                }
+                body.insert("init_images".to_string(), json!([STANDARD.encode(image)]));
+                "img2img"
+            }
+            None => "txt2img",
+        };
+
+        debug!("Sending request to AUTOMATIC1111 API");
+        let api_url = format!("{}/sdapi/v1/{endpoint}", self.base_url);
+        let response = send_with_retry(
+            self.client.post(api_url).json(&body),
+            &self.retry,
+            self.timeout,
+            "automatic1111.rs",
+        )
+        .await?;
+
+        // Handle the response
+        if response.status().is_success() {
+            debug!("Parsing response from AUTOMATIC1111 API");
+            let a1111_response: Automatic1111Response = response.json().await?;
+
+            if a1111_response.images.is_empty() {
+                bail!(CodedError::new(
+                    ErrorCode::Provider,
+                    "No image data found in response (automatic1111.rs)"
+                ))
+            }
+
+            let info: Automatic1111Info = a1111_response
+                .info
+                .as_deref()
+                .and_then(|info| serde_json::from_str(info).ok())
+                .unwrap_or_default();
+
+            // Ignore extra images (e.g., ControlNet detect maps) beyond the batch
+            let mut results = Vec::with_capacity(n as usize);
+            for (i, image) in a1111_response.images.iter().take(n as usize).enumerate() {
+                let image_bytes = STANDARD
+                    .decode(image)
+                    .context("Failed to decode base64 image (automatic1111.rs)")?;
+
+                let mut result = GenerationResult::new(image_bytes, None, request, &request_body, start.elapsed());
+                result.seed = info
+                    .all_seeds
+                    .get(i)
+                    .copied()
+                    .or_else(|| info.seed.map(|s| s.wrapping_add(i as u64)))
+                    .or(request.seed);
+                results.push(result);
+            }
+            Ok(results)
+        } else {
+            // Error generating image
+            let status = response.status();
+            let body = response.text().await?;
+            let message = match serde_json::from_str::<Automatic1111ErrorResponse>(&body) {
+                Ok(Automatic1111ErrorResponse {
+                    detail: Some(serde_json::Value::String(detail)),
+                    ..
+                }) => detail,
+                Ok(Automatic1111ErrorResponse { error: Some(error), .. }) => error,
+                _ => format!("{status}: {}", body.trim()),
+            };
+            bail!(CodedError::new(
+                ErrorCode::Provider,
+                format!("{message} (automatic1111.rs)")
+            ))
+        }
+    }
+}
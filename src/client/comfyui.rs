@@ -0,0 +1,452 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use image::guess_format;
+use log::debug;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest::multipart::{Form, Part};
+use serde_json::{json, Value};
+
+use crate::error::{CodedError, ErrorCode};
+use crate::request::GenerationRequest;
+use crate::result::GenerationResult;
+use crate::services::{Model, Service, ServiceKind, Workflow};
+
+use super::retry::{send_multipart_with_retry, send_with_retry, RetryPolicy};
+use super::Client;
+
+const DEFAULT_URL: &str = "http://127.0.0.1:8188";
+
+/// Time between history polls while the prompt is queued or running
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Response from queueing a prompt
+#[derive(serde::Deserialize, Debug)]
+struct ComfyUIPromptResponse {
+    prompt_id: String,
+}
+
+/// Response from uploading an image
+#[derive(serde::Deserialize, Debug)]
+struct ComfyUIUpload {
+    name: String,
+    #[serde(default)]
+    subfolder: String,
+}
+
+/// Error response from queueing a prompt (e.g., an invalid workflow)
+#[derive(serde::Deserialize, Debug)]
+struct ComfyUIErrorResponse {
+    error: ComfyUIError,
+    #[serde(default)]
+    node_errors: Value,
+}
+
+/// Error response entity
+#[derive(serde::Deserialize, Debug)]
+struct ComfyUIError {
+    message: String,
+    #[serde(default)]
+    details: String,
+}
+
+/// History entry for a prompt
+#[derive(serde::Deserialize, Debug)]
+struct ComfyUIHistory {
+    #[serde(default)]
+    outputs: BTreeMap<String, ComfyUINodeOutput>,
+    status: Option<ComfyUIStatus>,
+}
+
+/// Execution status of a prompt
+#[derive(serde::Deserialize, Debug)]
+struct ComfyUIStatus {
+    status_str: String,
+    #[serde(default)]
+    completed: bool,
+    #[serde(default)]
+    messages: Vec<Value>,
+}
+
+/// Outputs of a node
+#[derive(serde::Deserialize, Debug)]
+struct ComfyUINodeOutput {
+    #[serde(default)]
+    images: Vec<ComfyUIImage>,
+}
+
+/// Image reference for `/view`
+#[derive(serde::Deserialize, Debug)]
+struct ComfyUIImage {
+    filename: String,
+    #[serde(default)]
+    subfolder: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// ComfyUI client that queues workflow templates
+#[derive(Debug)]
+pub struct ComfyUIClient {
+    pub client: reqwest::Client,
+    pub base_url: String,
+    pub timeout: u64,
+    pub retry: RetryPolicy,
+}
+
+/// Set a node input from a `<node id>.<input name>` reference
+fn set_input(
+    workflow: &mut Value,
+    reference: &str,
+    value: Value,
+    parameters: &mut HashMap<String, Value>,
+) -> Result<()> {
+    let Some((node, input)) = reference.split_once('.') else {
+        bail!(CodedError::new(
+            ErrorCode::Config,
+            format!("Invalid node input `{reference}`, expected `<node id>.<input name>` (comfyui.rs)")
+        ));
+    };
+    let Some(inputs) = workflow
+        .get_mut(node)
+        .and_then(|n| n.get_mut("inputs"))
+        .and_then(Value::as_object_mut)
+    else {
+        bail!(CodedError::new(
+            ErrorCode::Config,
+            format!("Node `{node}` not found in workflow (comfyui.rs)")
+        ));
+    };
+    inputs.insert(input.to_string(), value.clone());
+    parameters.insert(reference.to_string(), value);
+    Ok(())
+}
+
+/// Load a workflow and substitute the request parameters into its node inputs
+/// Returns the workflow and the substituted inputs
+fn render_workflow(
+    workflow: &Workflow,
+    request: &GenerationRequest,
+    seed: u64,
+    image: Option<String>,
+    mask: Option<String>,
+) -> Result<(Value, HashMap<String, Value>)> {
+    let display = workflow.path.display();
+    let contents =
+        fs::read_to_string(&workflow.path).context(format!("Failed to read workflow `{display}` (comfyui.rs)"))?;
+    let mut graph: Value = serde_json::from_str(&contents).map_err(|e| {
+        CodedError::new(
+            ErrorCode::Config,
+            format!("Invalid workflow `{display}`: {e} (comfyui.rs)"),
+        )
+    })?;
+
+    let mut parameters = HashMap::new();
+    set_input(&mut graph, &workflow.prompt, json!(request.prompt), &mut parameters)?;
+
+    let values = [
+        (
+            &workflow.negative_prompt,
+            request.get_negative_prompt().map(|n| json!(n)),
+        ),
+        (&workflow.seed, Some(json!(seed))),
+        (&workflow.steps, request.get_steps().map(|s| json!(s))),
+        (&workflow.cfg, request.get_cfg().map(|c| json!(c))),
+        (&workflow.width, request.get_width().map(|w| json!(w))),
+        (&workflow.height, request.get_height().map(|h| json!(h))),
+        (&workflow.model, Some(json!(request.model.name))),
+        (&workflow.image, image.map(|i| json!(i))),
+        (&workflow.mask, mask.map(|m| json!(m))),
+        (&workflow.strength, request.strength.map(|s| json!(s))),
+    ];
+    for (reference, value) in values {
+        if let (Some(reference), Some(value)) = (reference, value) {
+            set_input(&mut graph, reference, value, &mut parameters)?;
+        }
+    }
+
+    Ok((graph, parameters))
+}
+
+/// Random seed from the standard library's randomly keyed hasher
+fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+impl ComfyUIClient {
+    /// Upload an image to the input folder and return the name for `LoadImage` or `LoadImageMask`
+    /// Names are derived from the contents so concurrent uploads of different images don't collide
+    async fn upload_image(
+        &self,
+        image: Vec<u8>,
+    ) -> Result<String> {
+        let format = guess_format(&image)?;
+        let extension = format.extensions_str().first().copied().unwrap_or("png");
+        let file_name = format!("txt2img-{:08x}.{extension}", crc32fast::hash(&image));
+
+        debug!("Uploading {file_name} to ComfyUI");
+        let upload_url = format!("{}/upload/image", self.base_url);
+        let form = || -> Result<Form> {
+            let part = Part::bytes(image.clone())
+                .file_name(file_name.clone())
+                .mime_str(format.to_mime_type())?;
+            Ok(Form::new().part("image", part).text("overwrite", "true"))
+        };
+        let response = send_multipart_with_retry(
+            self.client.post(upload_url),
+            form,
+            &self.retry,
+            self.timeout,
+            "comfyui.rs",
+        )
+        .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await?;
+            bail!(CodedError::new(
+                ErrorCode::Provider,
+                format!("Failed to upload image: {status}: {} (comfyui.rs)", body.trim())
+            ))
+        }
+
+        let upload: ComfyUIUpload = response.json().await?;
+        if upload.subfolder.is_empty() {
+            Ok(upload.name)
+        } else {
+            Ok(format!("{}/{}", upload.subfolder, upload.name))
+        }
+    }
+}
+
+/// Parameters without a node input in the workflow are dropped
+pub(super) fn supported_parameters(model: &Model) -> Vec<&'static str> {
+    let Some(workflow) = &model.workflow else {
+        return Vec::new();
+    };
+    let inputs = [
+        ("negative_prompt", &workflow.negative_prompt),
+        ("width", &workflow.width),
+        ("height", &workflow.height),
+        ("steps", &workflow.steps),
+        ("cfg", &workflow.cfg),
+        ("seed", &workflow.seed),
+    ];
+    inputs
+        .iter()
+        .filter(|(_, input)| input.is_some())
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl Client for ComfyUIClient {
+    fn new(
+        service: &Service,
+        timeout: u64,
+        retry: RetryPolicy,
+    ) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+
+        // https://docs.comfy.org/development/comfyui-server/comms_routes
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        debug!("Creating ComfyUI client for `{}`", service.id);
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(timeout))
+            .build()
+            .context("System network error (comfyui.rs)")?;
+
+        let base_url = service.get_base_url(DEFAULT_URL)?;
+        debug!("Using base URL {base_url}");
+
+        Ok(Self {
+            client,
+            base_url,
+            timeout,
+            retry,
+        })
+    }
+
+    /// Workflows take an init image if they map one
+    fn supports_init_image(
+        &self,
+        model: &Model,
+    ) -> bool {
+        model.workflow.as_ref().is_some_and(|w| w.image.is_some())
+    }
+
+    fn supports_strength(
+        &self,
+        model: &Model,
+    ) -> bool {
+        model.workflow.as_ref().is_some_and(|w| w.strength.is_some())
+    }
+
+    fn supports_mask(
+        &self,
+        model: &Model,
+    ) -> bool {
+        model.workflow.as_ref().is_some_and(|w| w.mask.is_some())
+    }
+
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn kind(&self) -> ServiceKind {
+        ServiceKind::Comfyui
+    }
+
+    /// Queue the workflow, wait for it to finish, and download its output images
+    /// The workflow decides how many images it produces, so `n` is ignored
+    async fn generate_images(
+        &self,
+        request: &GenerationRequest,
+        _n: u8,
+    ) -> Result<Vec<GenerationResult>> {
+        let start = Instant::now();
+        let model = &request.model;
+        let workflow = model
+            .workflow
+            .as_ref()
+            .context(format!("Model `{}` has no workflow (comfyui.rs)", model.id))?;
+
+        // Workflows have a fixed seed, so pick one to get a new image each time
+        let seed = request.seed.unwrap_or_else(random_seed);
+        let (image, mask) = match request.read_init_image()? {
+            Some(image) => {
+                let mask = match request.read_mask(&image)? {
+                    Some(mask) => Some(self.upload_image(mask.to_png()?).await?),
+                    None => None,
+                };
+                (Some(self.upload_image(image).await?), mask)
+            }
+            None => (None, None),
+        };
+        let (graph, parameters) = render_workflow(workflow, request, seed, image, mask)?;
+
+        debug!("Queueing workflow on ComfyUI");
+        let prompt_url = format!("{}/prompt", self.base_url);
+        let response = send_with_retry(
+            self.client.post(prompt_url).json(&json!({ "prompt": graph })),
+            &self.retry,
+            self.timeout,
+            "comfyui.rs",
+        )
+        .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await?;
+            let message = match serde_json::from_str::<ComfyUIErrorResponse>(&body) {
+                // Validation errors list the failing nodes separately
+                Ok(ComfyUIErrorResponse { error, node_errors }) => {
+                    let mut message = error.message;
+                    if !error.details.is_empty() {
+                        message = format!("{message}: {}", error.details);
+                    }
+                    match node_errors.as_object() {
+                        Some(nodes) if !nodes.is_empty() => format!("{message} {node_errors}"),
+                        _ => message,
+                    }
+                }
+                Err(_) => format!("{status}: {}", body.trim()),
+            };
+            bail!(CodedError::new(ErrorCode::Provider, format!("{message} (comfyui.rs)")))
+        }
+        let prompt_id = response.json::<ComfyUIPromptResponse>().await?.prompt_id;
+
+        // Poll the history until the prompt shows up as finished
+        let history_url = format!("{}/history/{prompt_id}", self.base_url);
+        let history = loop {
+            if start.elapsed() > Duration::from_secs(self.timeout) {
+                bail!(CodedError::new(
+                    ErrorCode::Timeout,
+                    format!("Workflow didn't finish after {} seconds (comfyui.rs)", self.timeout)
+                ))
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+
+            debug!("Polling ComfyUI history for {prompt_id}");
+            let response =
+                send_with_retry(self.client.get(&history_url), &self.retry, self.timeout, "comfyui.rs").await?;
+            let mut entries: HashMap<String, ComfyUIHistory> = response.json().await?;
+            match entries.remove(&prompt_id) {
+                Some(entry) if entry.status.as_ref().is_some_and(|s| s.status_str == "error") => {
+                    let messages = entry.status.map(|s| s.messages).unwrap_or_default();
+                    bail!(CodedError::new(
+                        ErrorCode::Provider,
+                        format!("Workflow failed: {} (comfyui.rs)", json!(messages))
+                    ))
+                }
+                // Older servers don't report a status, so outputs alone mean it finished
+                Some(entry) if !matches!(&entry.status, Some(status) if !status.completed) => break entry,
+                _ => continue,
+            }
+        };
+
+        // Prefer saved images over previews when the workflow has both
+        let images: Vec<_> = history.outputs.values().flat_map(|o| &o.images).collect();
+        let saved: Vec<_> = images.iter().filter(|i| i.kind == "output").copied().collect();
+        let images = if saved.is_empty() { images } else { saved };
+
+        if images.is_empty() {
+            bail!(CodedError::new(
+                ErrorCode::Provider,
+                "No images found in workflow outputs (comfyui.rs)"
+            ))
+        }
+
+        let view_url = format!("{}/view", self.base_url);
+        let mut results = Vec::with_capacity(images.len());
+        for image in images {
+            debug!("Fetching {}", image.filename);
+            let query = [
+                ("filename", &image.filename),
+                ("subfolder", &image.subfolder),
+                ("type", &image.kind),
+            ];
+            let response_image = send_with_retry(
+                self.client.get(&view_url).query(&query),
+                &self.retry,
+                self.timeout,
+                "comfyui.rs",
+            )
+            .await?;
+
+            if !response_image.status().is_success() {
+                bail!(CodedError::new(
+                    ErrorCode::Provider,
+                    format!(
+                        "Failed to fetch {} after successful generation (comfyui.rs)",
+                        image.filename
+                    )
+                ))
+            }
+
+            let content_type = response_image
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let bytes = response_image.bytes().await?;
+
+            let mut result = GenerationResult::new(
+                bytes.to_vec(),
+                content_type.as_deref(),
+                request,
+                &parameters,
+                start.elapsed(),
+            );
+            result.seed = workflow.seed.as_ref().map(|_| seed);
+            results.push(result);
+        }
+        Ok(results)
+    }
+}
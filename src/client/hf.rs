@@ -1,18 +1,22 @@
 use std::collections::HashMap;
 use std::env;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use log::debug;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use serde_json::json;
 
-use crate::cli::Cli;
+use crate::error::{CodedError, ErrorCode};
+use crate::request::GenerationRequest;
+use crate::result::GenerationResult;
+use crate::services::{Model, Service, ServiceKind};
 
+use super::retry::{send_with_retry, RetryPolicy};
 use super::Client;
 
 const ENV: &str = "HF_TOKEN";
-const URL: &str = "https://api-inference.huggingface.co/models";
+const DEFAULT_URL: &str = "https://api-inference.huggingface.co/models";
 
 /// Request body for the Hugging Face API
 #[derive(serde::Serialize, Debug)]
@@ -30,12 +34,24 @@ struct HuggingFaceErrorResponse {
 #[derive(Debug)]
 pub struct HuggingFaceClient {
     pub client: reqwest::Client,
+    pub base_url: String,
+    pub timeout: u64,
+    pub retry: RetryPolicy,
+}
+
+pub(super) fn supported_parameters(_model: &Model) -> Vec<&'static str> {
+    vec!["negative_prompt", "width", "height", "steps", "cfg", "seed"]
 }
 
 #[async_trait::async_trait]
 impl Client for HuggingFaceClient {
-    fn new(timeout: u64) -> Result<Self> {
-        let token = env::var(ENV).context(format!("`{ENV}` not set (hf.rs)"))?;
+    fn new(
+        service: &Service,
+        timeout: u64,
+        retry: RetryPolicy,
+    ) -> Result<Self> {
+        let token =
+            env::var(ENV).map_err(|_| CodedError::new(ErrorCode::MissingApiKey, format!("`{ENV}` not set (hf.rs)")))?;
         let mut headers = HeaderMap::new();
 
         // https://huggingface.co/docs/api-inference/en/parameters
@@ -64,83 +80,108 @@ impl Client for HuggingFaceClient {
             .build()
             .context("System network error (hf.rs)")?;
 
-        Ok(Self { client })
+        let base_url = service.get_base_url(DEFAULT_URL)?;
+        debug!("Using base URL {base_url}");
+
+        Ok(Self {
+            client,
+            base_url,
+            timeout,
+            retry,
+        })
+    }
+
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn kind(&self) -> ServiceKind {
+        ServiceKind::Hf
     }
 
     /// Generate an image using the Hugging Face API
-    async fn generate_image(
+    /// The API returns a single image per request, so `n` is ignored
+    async fn generate_images(
         &self,
-        cli: &Cli,
-    ) -> Result<Vec<u8>> {
-        let model = cli.get_model()?;
+        request: &GenerationRequest,
+        _n: u8,
+    ) -> Result<Vec<GenerationResult>> {
+        let start = Instant::now();
+        let model = &request.model;
         let mut parameters = HashMap::new();
 
-        if model.width.is_some() {
-            let width = cli.get_width()?;
+        // Send parameters set by the user or the model (ad-hoc repo IDs have no defaults)
+        if let Some(width) = request.get_width() {
             parameters.insert("width".to_string(), json!(width));
         }
 
-        if model.height.is_some() {
-            let height = cli.get_height()?;
+        if let Some(height) = request.get_height() {
             parameters.insert("height".to_string(), json!(height));
         }
 
-        if model.cfg.is_some() {
-            let cfg = cli.get_cfg()?;
+        if let Some(cfg) = request.get_cfg() {
             parameters.insert("guidance_scale".to_string(), json!(cfg));
         }
 
-        if model.steps.is_some() {
-            let steps = cli.get_steps()?;
+        if let Some(steps) = request.get_steps() {
             parameters.insert("num_inference_steps".to_string(), json!(steps));
         }
 
-        if model.negative_prompt.is_some() {
-            let negative_prompt = cli.get_negative_prompt()?;
+        if let Some(negative_prompt) = request.get_negative_prompt() {
             parameters.insert("negative_prompt".to_string(), json!(negative_prompt));
         }
 
         // Add seed if present
-        if let Some(seed) = cli.seed {
+        if let Some(seed) = request.seed {
             parameters.insert("seed".to_string(), json!(seed));
         }
 
         // Add options if present
-        if let Some(options) = &model.options {
-            for (key, value) in options {
-                parameters.insert(key.clone(), value.clone());
-            }
+        for (key, value) in request.get_options() {
+            parameters.insert(key, value);
         }
 
         // Append the model ID to the base URL
-        let api_url = format!("{URL}/{}", model.name);
+        let api_url = format!("{}/{}", self.base_url, model.name);
 
-        // Get the prompt (can safely unwrap because it's required by Clap)
-        let inputs = cli.prompt.as_deref().unwrap().to_string();
+        let inputs = request.prompt.clone();
 
         // Build the request body
         let request_body = HuggingFaceRequest { parameters, inputs };
 
         // Send the request
         debug!("Sending request to Hugging Face API");
-        let response = match self.client.post(api_url).json(&request_body).send().await {
-            Ok(response) => response,
-            Err(e) if e.is_timeout() => {
-                bail!("Request timed out after {} seconds (hf.rs)", cli.timeout)
-            }
-            Err(e) => {
-                bail!("{e} (hf.rs)")
-            }
-        };
+        let response = send_with_retry(
+            self.client.post(api_url).json(&request_body),
+            &self.retry,
+            self.timeout,
+            "hf.rs",
+        )
+        .await?;
 
         // Handle the response
         if response.status().is_success() {
             debug!("Parsing response from Hugging Face API");
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
             let bytes = response.bytes().await?;
-            Ok(bytes.to_vec())
+            let result = GenerationResult::new(
+                bytes.to_vec(),
+                content_type.as_deref(),
+                request,
+                &request_body.parameters,
+                start.elapsed(),
+            );
+            Ok(vec![result])
         } else {
             let error_response: HuggingFaceErrorResponse = response.json().await?;
-            bail!("{} (hf.rs)", error_response.error)
+            bail!(CodedError::new(
+                ErrorCode::Provider,
+                format!("{} (hf.rs)", error_response.error)
+            ))
         }
     }
 }
@@ -1,50 +1,293 @@
+mod automatic1111;
+mod comfyui;
 mod hf;
 mod openai;
+mod retry;
 mod together;
 
-use anyhow::Result;
+use std::time::Duration;
 
-use crate::cli::Cli;
-use crate::services::ServiceId;
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
 
+use crate::cache::Cache;
+use crate::error::{CodedError, ErrorCode};
+use crate::request::{GenerationRequest, SizeSource};
+use crate::result::GenerationResult;
+use crate::services::{get_or_init_services, Model, Service, ServiceId, ServiceKind};
+
+pub use self::automatic1111::Automatic1111Client;
+pub use self::comfyui::ComfyUIClient;
 pub use self::hf::HuggingFaceClient;
 pub use self::openai::OpenAIClient;
+pub use self::retry::RetryPolicy;
 pub use self::together::TogetherClient;
 
+// Send + Sync so clients can be used across tasks when running prompts concurrently
 #[async_trait::async_trait]
-pub trait Client {
+pub trait Client: Send + Sync {
     // The where clause prevents `new` from being called on trait objects (e.g., `dyn Client`).
     // Trait objects are unsized, and returning `Self` requires the size to be known at compile-time.
-    fn new(timeout: u64) -> Result<Self>
+    fn new(
+        service: &Service,
+        timeout: u64,
+        retry: RetryPolicy,
+    ) -> Result<Self>
     where
         Self: Sized;
 
-    async fn generate_image(
+    /// Maximum number of images the provider can return from a single request
+    fn max_batch_size(
+        &self,
+        _model: &Model,
+    ) -> u8 {
+        1
+    }
+
+    /// Whether the model can start from an init image (image-to-image)
+    fn supports_init_image(
+        &self,
+        _model: &Model,
+    ) -> bool {
+        false
+    }
+
+    /// Whether the model accepts a strength for the init image
+    fn supports_strength(
+        &self,
+        _model: &Model,
+    ) -> bool {
+        false
+    }
+
+    /// Whether the model can inpaint the masked area of the init image
+    fn supports_mask(
+        &self,
+        _model: &Model,
+    ) -> bool {
+        false
+    }
+
+    /// Whether the prompt is dropped for the request (e.g., DALL-E 2 variations of an init image)
+    fn ignores_prompt(
         &self,
-        cli: &Cli,
-    ) -> Result<Vec<u8>>;
+        _request: &GenerationRequest,
+    ) -> bool {
+        false
+    }
+
+    /// Resolved base URL of the provider
+    fn base_url(&self) -> &str;
+
+    /// API the client speaks
+    fn kind(&self) -> ServiceKind;
+
+    /// Generate `n` images in a single request
+    async fn generate_images(
+        &self,
+        request: &GenerationRequest,
+        n: u8,
+    ) -> Result<Vec<GenerationResult>>;
+}
+
+/// Client without the API key for downloading result URLs, which can point at any host (e.g., a CDN)
+fn download_client(
+    timeout: u64,
+    source: &str,
+) -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .build()
+        .context(format!("System network error ({source})"))
+}
+
+/// Optional parameters sent to the provider for the model, others are dropped
+/// Only depends on the service type, so models can be listed without credentials
+pub fn supported_parameters(
+    kind: ServiceKind,
+    model: &Model,
+) -> Vec<&'static str> {
+    match kind {
+        ServiceKind::Hf => hf::supported_parameters(model),
+        ServiceKind::Openai | ServiceKind::OpenaiCompatible => openai::supported_parameters(kind, model),
+        ServiceKind::Together => together::supported_parameters(model),
+        ServiceKind::Automatic1111 => automatic1111::supported_parameters(model),
+        ServiceKind::Comfyui => comfyui::supported_parameters(model),
+    }
+}
+
+/// Error before sending a request with parameters the model can't use
+fn check_capabilities(
+    client: &dyn Client,
+    request: &GenerationRequest,
+) -> Result<()> {
+    let model = &request.model;
+    let unsupported = if request.init_image.is_some() && !client.supports_init_image(model) {
+        Some("an init image")
+    } else if request.strength.is_some() && !client.supports_strength(model) {
+        Some("a strength")
+    } else if request.mask.is_some() && !client.supports_mask(model) {
+        Some("a mask")
+    } else {
+        None
+    };
+    if let Some(parameter) = unsupported {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "Model `{}` on service `{}` doesn't support {parameter} (mod.rs)",
+                model.id, request.service
+            )
+        ));
+    }
+    Ok(())
+}
+
+/// Warn about parameters the model ignores, or error if `strict`
+/// Only parameters set on the request are checked, not the model defaults
+pub fn check_ignored_parameters(
+    client: &dyn Client,
+    request: &GenerationRequest,
+    strict: bool,
+) -> Result<()> {
+    let set = [
+        ("negative_prompt", request.negative_prompt.is_some()),
+        ("width", request.width.is_some()),
+        ("height", request.height.is_some()),
+        ("steps", request.steps.is_some()),
+        ("cfg", request.cfg.is_some()),
+        ("seed", request.seed.is_some()),
+        ("style", request.style.is_some()),
+        ("sampler", request.sampler.is_some()),
+        ("quality", request.quality.is_some()),
+        ("background", request.background.is_some()),
+        ("compression", request.compression.is_some()),
+        ("moderation", request.moderation.is_some()),
+    ];
+    let supported = supported_parameters(client.kind(), &request.model);
+    let mut ignored: Vec<String> = set
+        .iter()
+        .filter(|(name, set)| *set && !supported.contains(name))
+        .map(|(name, _)| match (*name, request.size_source) {
+            ("width" | "height", SizeSource::Size) => "--size".to_string(),
+            ("width" | "height", SizeSource::Aspect) => "--aspect".to_string(),
+            _ => format!("--{}", name.replace('_', "-")),
+        })
+        .collect();
+    // Width and height both come from a single --size or --aspect
+    ignored.dedup();
+    if client.ignores_prompt(request) {
+        ignored.insert(0, "the prompt without --mask".to_string());
+    }
+    if ignored.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "Model `{}` on service `{}` ignores {}",
+        request.model.id,
+        request.service,
+        ignored.join(", ")
+    );
+    if strict {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            format!("{message} (mod.rs)")
+        ));
+    }
+    warn!("{message}");
+    Ok(())
+}
+
+/// Generate `count` images, batching requests where the provider supports it
+/// When a seed is set, each image gets its own request with the seed incremented so it can be reproduced,
+/// and is looked up in the cache if the provider honors the seed
+pub async fn generate_batch(
+    client: &dyn Client,
+    request: &GenerationRequest,
+    count: u8,
+    cache: Option<&Cache>,
+) -> Result<Vec<GenerationResult>> {
+    check_capabilities(client, request)?;
+    let mut results = Vec::with_capacity(count as usize);
+
+    if let Some(seed) = request.seed {
+        let cache = cache.filter(|_| supported_parameters(client.kind(), &request.model).contains(&"seed"));
+        for i in 0..count {
+            let mut request = request.clone();
+            request.seed = Some(seed.wrapping_add(i as u64));
+            // The key reads the input files, so it's only computed when the cache is used
+            let key = match cache {
+                Some(cache) => Cache::key(&request, client.base_url())?.map(|key| (cache, key)),
+                None => None,
+            };
+            let Some((cache, key)) = key else {
+                results.extend(client.generate_images(&request, 1).await?);
+                continue;
+            };
+
+            // A broken cache entry shouldn't stop the generation
+            match cache.get(&key, &request) {
+                Ok(Some(result)) => {
+                    debug!("Using cached image {key}");
+                    results.push(result);
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Ignoring cache entry {key}: {e}"),
+            }
+            // Workflows can return several images, which one entry can't hold
+            let generated = client.generate_images(&request, 1).await?;
+            if let [result] = generated.as_slice() {
+                if let Err(e) = cache.put(&key, result) {
+                    warn!("Failed to cache image: {e}");
+                }
+            }
+            results.extend(generated);
+        }
+        return Ok(results);
+    }
+
+    let max_batch_size = client.max_batch_size(&request.model).max(1);
+    let mut remaining = count;
+    while remaining > 0 {
+        let n = remaining.min(max_batch_size);
+        results.extend(client.generate_images(request, n).await?);
+        remaining -= n;
+    }
+    Ok(results)
 }
 
-/// Create a client based on the service
+/// Create a client based on the service type
 pub fn create_client(
     service: &ServiceId,
     timeout: &u64,
+    retry: RetryPolicy,
     // The `dyn` keyword is used to create a trait object.
     // We return a boxed trait object for runtime polymorphism, so we can handle different types of clients.
 ) -> Result<Box<dyn Client>> {
+    let service = get_or_init_services().get(service)?;
     // Dereference the timeout
     let timeout_value = *timeout;
-    match service {
-        ServiceId::Hf => {
-            let client = HuggingFaceClient::new(timeout_value)?;
+    match service.kind {
+        ServiceKind::Hf => {
+            let client = HuggingFaceClient::new(service, timeout_value, retry)?;
+            Ok(Box::new(client))
+        }
+        ServiceKind::Openai | ServiceKind::OpenaiCompatible => {
+            let client = OpenAIClient::new(service, timeout_value, retry)?;
+            Ok(Box::new(client))
+        }
+        ServiceKind::Together => {
+            let client = TogetherClient::new(service, timeout_value, retry)?;
             Ok(Box::new(client))
         }
-        ServiceId::Openai => {
-            let client = OpenAIClient::new(timeout_value)?;
+        ServiceKind::Automatic1111 => {
+            let client = Automatic1111Client::new(service, timeout_value, retry)?;
             Ok(Box::new(client))
         }
-        ServiceId::Together => {
-            let client = TogetherClient::new(timeout_value)?;
+        ServiceKind::Comfyui => {
+            let client = ComfyUIClient::new(service, timeout_value, retry)?;
             Ok(Box::new(client))
         }
     }
@@ -1,25 +1,40 @@
 use std::collections::HashMap;
 use std::env;
-use std::time::Duration;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
+use image::{guess_format, ImageFormat, ImageReader};
 use log::debug;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
-use serde_json::json;
+use reqwest::multipart::{Form, Part};
+use serde_json::{json, Value};
 
-use crate::cli::Cli;
+use crate::error::{CodedError, ErrorCode};
+use crate::output::ImageFileFormat;
+use crate::request::GenerationRequest;
+use crate::result::GenerationResult;
+use crate::services::{Model, OpenAIImageBackground, OpenAIImageQuality, ResponseFormat, Service, ServiceKind};
 
-use super::Client;
+use super::retry::{send_multipart_with_retry, send_with_retry, RetryPolicy};
+use super::{download_client, Client};
 
 const ENV: &str = "OPENAI_API_KEY";
-const URL: &str = "https://api.openai.com/v1";
+const DEFAULT_URL: &str = "https://api.openai.com/v1";
+
+/// Largest image DALL-E 2 accepts for edits and variations
+const DALLE2_MAX_IMAGE_BYTES: usize = 4 * 1024 * 1024;
 
 /// Image response entity
+/// Either `b64_json` or `url` is set depending on the response format
 #[derive(serde::Deserialize, Debug)]
 struct OpenAIImage {
-    b64_json: String,
+    #[serde(default)]
+    b64_json: Option<String>,
+    #[serde(default)]
+    url: Option<String>,
     // Default to None instead of a deserialization error
     #[serde(default)]
     revised_prompt: Option<String>,
@@ -43,74 +58,378 @@ struct OpenAIErrorResponse {
     error: OpenAIError,
 }
 
-/// OpenAI API client
+/// GPT Image models always return base64 and reject `style` and `response_format`
+fn is_gpt_image(model: &Model) -> bool {
+    model.name.starts_with("gpt-image")
+}
+
+/// Check the options against the model before sending, since OpenAI rejects what a model doesn't support
+fn validate_options(request: &GenerationRequest) -> Result<()> {
+    let model = &request.model;
+    let gpt_image = is_gpt_image(model);
+
+    let gpt_image_only = [
+        ("background", request.background.is_some()),
+        ("compression", request.compression.is_some()),
+        ("moderation", request.moderation.is_some()),
+    ];
+    if let Some((name, _)) = gpt_image_only.iter().find(|(_, set)| *set && !gpt_image) {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "Model `{}` doesn't support {name}, only GPT Image does (openai.rs)",
+                model.id
+            )
+        ));
+    }
+
+    if let Some(quality) = request.quality {
+        let allowed: &[OpenAIImageQuality] = match model.name.as_str() {
+            _ if gpt_image => &[
+                OpenAIImageQuality::Auto,
+                OpenAIImageQuality::Low,
+                OpenAIImageQuality::Medium,
+                OpenAIImageQuality::High,
+            ],
+            "dall-e-3" => &[OpenAIImageQuality::Standard, OpenAIImageQuality::Hd],
+            _ => &[OpenAIImageQuality::Standard],
+        };
+        if !allowed.contains(&quality) {
+            let allowed: Vec<String> = allowed.iter().map(|q| q.to_string()).collect();
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "Model `{}` doesn't support quality `{quality}`, use one of: {} (openai.rs)",
+                    model.id,
+                    allowed.join(", ")
+                )
+            ));
+        }
+    }
+
+    // GPT Image returns PNG unless asked otherwise
+    let output_format = request.output_format.unwrap_or(ImageFileFormat::Png);
+    if request.background == Some(OpenAIImageBackground::Transparent) && output_format == ImageFileFormat::Jpeg {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            "Transparent backgrounds need PNG or WebP (openai.rs)"
+        ));
+    }
+    if request.compression.is_some() && output_format == ImageFileFormat::Png {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            "Compression needs `--format jpeg` or `--format webp` (openai.rs)"
+        ));
+    }
+    Ok(())
+}
+
+/// Re-encode an image as PNG, which is the only format the image endpoints accept
+fn encode_png(image: Vec<u8>) -> Result<Vec<u8>> {
+    if guess_format(&image).ok() == Some(ImageFormat::Png) {
+        return Ok(image);
+    }
+    let decoded = image::load_from_memory(&image).context("Failed to decode init image (openai.rs)")?;
+    let mut png = Cursor::new(Vec::new());
+    decoded
+        .write_to(&mut png, ImageFormat::Png)
+        .context("Failed to encode init image (openai.rs)")?;
+    Ok(png.into_inner())
+}
+
+/// DALL-E 2 only takes square PNGs under 4 MB, so check before uploading rather than getting a 400
+fn check_dalle2_image(
+    name: &str,
+    png: &[u8],
+) -> Result<()> {
+    let (width, height) = ImageReader::with_format(Cursor::new(png), ImageFormat::Png)
+        .into_dimensions()
+        .context(format!("Failed to read the {name} size (openai.rs)"))?;
+    let megabytes = png.len() as f64 / (1024.0 * 1024.0);
+    if width != height {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            format!("DALL-E 2 needs a square {name}, got {width}x{height} (openai.rs)")
+        ));
+    }
+    if png.len() >= DALLE2_MAX_IMAGE_BYTES {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            format!("DALL-E 2 needs a {name} under 4 MB as PNG, got {megabytes:.1} MB at {width}x{height} (openai.rs)")
+        ));
+    }
+    Ok(())
+}
+
+/// Build a multipart form from the request body with PNG files (e.g., `image` and `mask`)
+fn image_form(
+    fields: &HashMap<String, Value>,
+    files: &[(&'static str, Vec<u8>)],
+) -> Result<Form> {
+    let mut form = Form::new();
+    for (key, value) in fields {
+        let text = match value {
+            Value::String(text) => text.clone(),
+            value => value.to_string(),
+        };
+        form = form.text(key.clone(), text);
+    }
+    for (name, bytes) in files {
+        let part = Part::bytes(bytes.clone())
+            .file_name(format!("{name}.png"))
+            .mime_str("image/png")?;
+        form = form.part(*name, part);
+    }
+    Ok(form)
+}
+
+/// OpenAI API client, also used for OpenAI-compatible servers
 #[derive(Debug)]
 pub struct OpenAIClient {
     pub client: reqwest::Client,
+    /// Downloads result URLs without the API key
+    pub download: reqwest::Client,
+    pub kind: ServiceKind,
+    pub base_url: String,
+    pub response_format: ResponseFormat,
+    pub timeout: u64,
+    pub retry: RetryPolicy,
+}
+
+/// OpenAI takes no negative prompt, steps, or guidance, and DALL-E has no seed
+pub(super) fn supported_parameters(
+    kind: ServiceKind,
+    model: &Model,
+) -> Vec<&'static str> {
+    let mut parameters = vec!["width", "height", "quality"];
+    if kind == ServiceKind::OpenaiCompatible || is_gpt_image(model) {
+        parameters.extend(["background", "compression", "moderation"]);
+    }
+    if model.style.is_some() {
+        parameters.push("style");
+    }
+    if kind == ServiceKind::OpenaiCompatible && model.capabilities.seed {
+        parameters.push("seed");
+    }
+    parameters
 }
 
 #[async_trait::async_trait]
 impl Client for OpenAIClient {
-    fn new(timeout: u64) -> Result<Self> {
-        let token = env::var(ENV).context(format!("`{ENV}` not set (openai.rs)"))?;
+    fn new(
+        service: &Service,
+        timeout: u64,
+        retry: RetryPolicy,
+    ) -> Result<Self> {
         let mut headers = HeaderMap::new();
 
         // https://platform.openai.com/docs/api-reference/images
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        headers.insert(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {token}"))?, // fails on invalid characters
-        );
 
-        debug!("Creating OpenAI client");
+        // Self-hosted servers often don't need a key, so one is only required if configured
+        let env_name = match (&service.api_key_env, service.kind) {
+            (Some(name), _) => Some(name.as_str()),
+            (None, ServiceKind::Openai) => Some(ENV),
+            (None, _) => None,
+        };
+        if let Some(env_name) = env_name {
+            let token = env::var(env_name)
+                .map_err(|_| CodedError::new(ErrorCode::MissingApiKey, format!("`{env_name}` not set (openai.rs)")))?;
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {token}"))?, // fails on invalid characters
+            );
+        }
+
+        debug!("Creating OpenAI client for `{}`", service.id);
         let client = reqwest::Client::builder()
             .default_headers(headers)
             .timeout(Duration::from_secs(timeout))
             .build()
             .context("System network error (openai.rs)")?;
 
-        Ok(Self { client })
+        let base_url = service.get_base_url(DEFAULT_URL)?;
+        debug!("Using base URL {base_url}");
+
+        Ok(Self {
+            client,
+            download: download_client(timeout, "openai.rs")?,
+            kind: service.kind,
+            base_url,
+            response_format: service.response_format.unwrap_or_default(),
+            timeout,
+            retry,
+        })
+    }
+
+    /// DALL-E 3 only supports `n=1`
+    fn max_batch_size(
+        &self,
+        model: &Model,
+    ) -> u8 {
+        if model.name == "dall-e-3" {
+            1
+        } else {
+            10
+        }
+    }
+
+    /// DALL-E 2 can make variations of an image, and both DALL-E 2 and GPT Image can edit one
+    fn supports_init_image(
+        &self,
+        model: &Model,
+    ) -> bool {
+        self.kind == ServiceKind::Openai && (model.name == "dall-e-2" || is_gpt_image(model))
+    }
+
+    /// Models that edit images can also inpaint the masked area
+    fn supports_mask(
+        &self,
+        model: &Model,
+    ) -> bool {
+        self.supports_init_image(model)
+    }
+
+    /// DALL-E 2 makes variations of an init image without a mask, which are based on the image alone
+    fn ignores_prompt(
+        &self,
+        request: &GenerationRequest,
+    ) -> bool {
+        let model = &request.model;
+        self.supports_init_image(model)
+            && !is_gpt_image(model)
+            && request.init_image.is_some()
+            && request.mask.is_none()
+    }
+
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn kind(&self) -> ServiceKind {
+        self.kind
     }
 
-    /// Generate an image using the OpenAI API
-    async fn generate_image(
+    /// Generate images using the OpenAI API
+    async fn generate_images(
         &self,
-        cli: &Cli,
-    ) -> Result<Vec<u8>> {
-        let model = cli.get_model()?;
+        request: &GenerationRequest,
+        n: u8,
+    ) -> Result<Vec<GenerationResult>> {
+        let start = Instant::now();
+        let model = &request.model;
+        let gpt_image = is_gpt_image(model);
         let mut request_body = HashMap::new();
 
-        let prompt = cli.prompt.as_deref().unwrap().to_string();
+        // Compatible servers get the options as-is
+        if self.kind == ServiceKind::Openai {
+            validate_options(request)?;
+        }
+
         request_body.insert("model".to_string(), json!(model.name));
-        request_body.insert("prompt".to_string(), json!(prompt));
-        request_body.insert("response_format".to_string(), json!("b64_json"));
-        request_body.insert("n".to_string(), json!(1));
+        request_body.insert("prompt".to_string(), json!(request.prompt));
+        request_body.insert("n".to_string(), json!(n));
 
-        let width = cli.get_width()?;
-        let height = cli.get_height()?;
-        request_body.insert("size".to_string(), json!(format!("{width}x{height}")));
+        if !gpt_image {
+            request_body.insert("response_format".to_string(), json!(self.response_format));
+        }
+
+        // Models on OpenAI-compatible servers may not have a default size
+        match (request.get_width(), request.get_height()) {
+            (Some(width), Some(height)) => {
+                request_body.insert("size".to_string(), json!(format!("{width}x{height}")));
+            }
+            _ if self.kind == ServiceKind::Openai => bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                "Model does not support width and height (openai.rs)"
+            )),
+            _ => {}
+        }
 
         if model.style.is_some() {
-            request_body.insert("style".to_string(), json!(cli.style));
+            request_body.insert("style".to_string(), json!(request.get_style()));
+        }
+
+        // There is no seed for DALL-E, but compatible servers often accept one
+        let supports_seed = self.kind == ServiceKind::OpenaiCompatible && model.capabilities.seed;
+        if let Some(seed) = request.seed.filter(|_| supports_seed) {
+            request_body.insert("seed".to_string(), json!(seed));
         }
 
         // Add options if present
-        // Note that there is no seed for DALL-E
-        if let Some(options) = &model.options {
-            for (key, value) in options {
-                request_body.insert(key.clone(), value.clone());
-            }
+        for (key, value) in request.get_options() {
+            request_body.insert(key, value);
+        }
+
+        // Options from the request override the model options (e.g., DALL-E 3 quality)
+        if let Some(quality) = request.quality {
+            request_body.insert("quality".to_string(), json!(quality));
+        }
+
+        if let Some(background) = request.background {
+            request_body.insert("background".to_string(), json!(background));
+        }
+
+        if let Some(compression) = request.compression {
+            request_body.insert("output_compression".to_string(), json!(compression));
         }
 
-        debug!("Sending request to OpenAI API");
-        let image_url = format!("{URL}/images/generations");
-        let response = match self.client.post(image_url).json(&request_body).send().await {
-            Ok(response) => response,
-            Err(e) if e.is_timeout() => {
-                bail!("Request timed out after {} seconds (openai.rs)", cli.timeout)
+        if let Some(moderation) = request.moderation {
+            request_body.insert("moderation".to_string(), json!(moderation));
+        }
+
+        // The output format is also used for the file, so only ask GPT Image for it
+        if let Some(output_format) = request.output_format.filter(|_| gpt_image) {
+            request_body.insert("output_format".to_string(), json!(output_format));
+        }
+
+        let response = match request.read_init_image()? {
+            Some(image) => {
+                let mask = request.read_mask(&image)?;
+                let mut files = vec![("image", encode_png(image)?)];
+                request_body.remove("style");
+
+                // Edits change the image (or the masked area), while variations are based on the image alone
+                let endpoint = match mask {
+                    Some(mask) => {
+                        files.push(("mask", mask.to_png()?));
+                        "edits"
+                    }
+                    None if gpt_image => "edits",
+                    // Warned about (or rejected with `--strict`) by `check_ignored_parameters`
+                    None => {
+                        request_body.remove("prompt");
+                        "variations"
+                    }
+                };
+
+                if model.name == "dall-e-2" {
+                    for (name, png) in &files {
+                        check_dalle2_image(name, png)?;
+                    }
+                }
+
+                debug!("Sending {endpoint} request to OpenAI API");
+                let image_url = format!("{}/images/{endpoint}", self.base_url);
+                send_multipart_with_retry(
+                    self.client.post(image_url),
+                    || image_form(&request_body, &files),
+                    &self.retry,
+                    self.timeout,
+                    "openai.rs",
+                )
+                .await?
             }
-            Err(e) => {
-                bail!("{e} (openai.rs)")
+            None => {
+                debug!("Sending request to OpenAI API");
+                let image_url = format!("{}/images/generations", self.base_url);
+                send_with_retry(
+                    self.client.post(image_url).json(&request_body),
+                    &self.retry,
+                    self.timeout,
+                    "openai.rs",
+                )
+                .await?
             }
         };
 
@@ -119,25 +438,72 @@ impl Client for OpenAIClient {
             debug!("Parsing response from OpenAI API");
             let image_response: OpenAIImageResponse = response.json().await?;
 
-            if let Some(image_data) = image_response.data.first() {
-                let b64_json = &image_data.b64_json;
+            if image_response.data.is_empty() {
+                bail!(CodedError::new(
+                    ErrorCode::Provider,
+                    "No image data found in response (openai.rs)"
+                ))
+            }
 
+            let mut results = Vec::with_capacity(image_response.data.len());
+            for image_data in image_response.data {
                 if let Some(revised_prompt) = &image_data.revised_prompt {
                     debug!("Revised prompt: {}", revised_prompt);
                 }
 
-                let image_bytes = STANDARD
-                    .decode(b64_json)
-                    .context("Failed to decode base64 image (openai.rs)")?;
-
-                Ok(image_bytes)
-            } else {
-                bail!("No image data found in response (openai.rs)")
+                let mut result = match (&image_data.b64_json, &image_data.url) {
+                    (Some(b64_json), _) => {
+                        let image_bytes = STANDARD
+                            .decode(b64_json)
+                            .context("Failed to decode base64 image (openai.rs)")?;
+                        GenerationResult::new(image_bytes, None, request, &request_body, start.elapsed())
+                    }
+                    (None, Some(url)) => {
+                        debug!("Fetching image result");
+                        let response_image =
+                            send_with_retry(self.download.get(url), &self.retry, self.timeout, "openai.rs").await?;
+                        if !response_image.status().is_success() {
+                            bail!(CodedError::new(
+                                ErrorCode::Provider,
+                                "Failed to fetch image after successful generation (openai.rs)"
+                            ))
+                        }
+                        let content_type = response_image
+                            .headers()
+                            .get(CONTENT_TYPE)
+                            .and_then(|v| v.to_str().ok())
+                            .map(str::to_string);
+                        let bytes = response_image.bytes().await?;
+                        let mut result = GenerationResult::new(
+                            bytes.to_vec(),
+                            content_type.as_deref(),
+                            request,
+                            &request_body,
+                            start.elapsed(),
+                        );
+                        result.url = Some(url.clone());
+                        result
+                    }
+                    (None, None) => bail!(CodedError::new(
+                        ErrorCode::Provider,
+                        "No image data found in response (openai.rs)"
+                    )),
+                };
+                result.seed = request.seed.filter(|_| supports_seed);
+                result.revised_prompt = image_data.revised_prompt;
+                results.push(result);
             }
+            Ok(results)
         } else {
             // Error generating image
-            let error_response: OpenAIErrorResponse = response.json().await?;
-            bail!("{} (openai.rs)", error_response.error.message)
+            // Compatible servers don't always use the OpenAI error shape, so fall back to the status and body
+            let status = response.status();
+            let body = response.text().await?;
+            let message = match serde_json::from_str::<OpenAIErrorResponse>(&body) {
+                Ok(error_response) => error_response.error.message,
+                Err(_) => format!("{status}: {}", body.trim()),
+            };
+            bail!(CodedError::new(ErrorCode::Provider, format!("{message} (openai.rs)")))
         }
     }
 }
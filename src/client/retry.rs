@@ -0,0 +1,251 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use log::debug;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::multipart::Form;
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+use crate::error::{CodedError, ErrorCode};
+
+/// Wait before the first retry, doubled for each attempt after that
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Retry policy for transient provider errors
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub retries: u8,
+    /// Longest wait between attempts, including `Retry-After`
+    pub max_wait: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            max_wait: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff for an attempt (0-based), capped at the max wait
+    fn backoff(
+        &self,
+        attempt: u8,
+    ) -> Duration {
+        BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt.into()))
+            .min(self.max_wait)
+    }
+}
+
+/// Body of a Hugging Face 503 while the model is loading
+#[derive(serde::Deserialize, Debug)]
+struct ModelLoading {
+    error: String,
+    estimated_time: Option<f64>,
+}
+
+/// Send a request, retrying connect errors, timeouts, 429, 502, 503, and 504
+/// The last response is returned as-is so the client can parse the provider's error
+/// `source` is the file name used as the error suffix (e.g., `hf.rs`)
+pub async fn send_with_retry(
+    request: RequestBuilder,
+    policy: &RetryPolicy,
+    timeout: u64,
+    source: &str,
+) -> Result<Response> {
+    // Bodies are JSON or empty, so the request can always be cloned
+    send_attempts(|| clone_request(&request, source), policy, timeout, source).await
+}
+
+/// Send a multipart request with the same retries as `send_with_retry`
+/// Multipart bodies are streamed and can't be cloned, so the form is rebuilt for each attempt
+pub async fn send_multipart_with_retry(
+    request: RequestBuilder,
+    form: impl Fn() -> Result<Form>,
+    policy: &RetryPolicy,
+    timeout: u64,
+    source: &str,
+) -> Result<Response> {
+    send_attempts(
+        || Ok(clone_request(&request, source)?.multipart(form()?)),
+        policy,
+        timeout,
+        source,
+    )
+    .await
+}
+
+/// Clone a request without a streamed body
+fn clone_request(
+    request: &RequestBuilder,
+    source: &str,
+) -> Result<RequestBuilder> {
+    match request.try_clone() {
+        Some(builder) => Ok(builder),
+        None => bail!(CodedError::new(
+            ErrorCode::Internal,
+            format!("Request can't be retried ({source})")
+        )),
+    }
+}
+
+/// Send requests from `build` until one succeeds or the retries run out
+async fn send_attempts(
+    build: impl Fn() -> Result<RequestBuilder>,
+    policy: &RetryPolicy,
+    timeout: u64,
+    source: &str,
+) -> Result<Response> {
+    let mut attempt = 0;
+    loop {
+        let builder = build()?;
+        let can_retry = attempt < policy.retries;
+
+        let wait = match builder.send().await {
+            Ok(response) if can_retry && is_retryable(response.status()) => {
+                let status = response.status();
+                let wait = retry_after(response.headers()).unwrap_or_else(|| policy.backoff(attempt));
+                match model_loading(response).await {
+                    Some(estimated) => {
+                        debug!("Model is loading (estimated {:.0}s)", estimated.as_secs_f32());
+                        estimated.max(wait)
+                    }
+                    None => {
+                        debug!("Attempt {} failed with {status}", attempt + 1);
+                        wait
+                    }
+                }
+            }
+            Ok(response) => return Ok(response),
+            Err(e) if can_retry && (e.is_timeout() || e.is_connect()) => {
+                debug!("Attempt {} failed: {e}", attempt + 1);
+                policy.backoff(attempt)
+            }
+            Err(e) if e.is_timeout() => {
+                bail!(CodedError::new(
+                    ErrorCode::Timeout,
+                    format!("Request timed out after {timeout} seconds ({source})")
+                ))
+            }
+            Err(e) => {
+                bail!(CodedError::new(ErrorCode::Network, format!("{e} ({source})")))
+            }
+        };
+
+        let wait = wait.min(policy.max_wait);
+        attempt += 1;
+        debug!(
+            "Retrying in {:.1}s (attempt {} of {})",
+            wait.as_secs_f32(),
+            attempt + 1,
+            policy.retries + 1
+        );
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Rate limits and gateway errors are usually transient
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Parse `Retry-After` as seconds or an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+/// Get the estimated load time from a Hugging Face "model is currently loading" response
+async fn model_loading(response: Response) -> Option<Duration> {
+    if response.status() != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+    let body: ModelLoading = response.json().await.ok()?;
+    if !body.error.contains("currently loading") {
+        return None;
+    }
+    Duration::try_from_secs_f64(body.estimated_time?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(1), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn caps_backoff_at_max_wait() {
+        let policy = RetryPolicy {
+            retries: 10,
+            max_wait: Duration::from_secs(5),
+        };
+        assert_eq!(policy.backoff(3), Duration::from_secs(5));
+        assert_eq!(policy.backoff(u8::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(retry_after(&headers("2")), Some(Duration::from_secs(2)));
+        assert_eq!(retry_after(&headers(" 1.5 ")), Some(Duration::from_millis(1500)));
+        assert_eq!(retry_after(&headers("-1")), None);
+    }
+
+    #[test]
+    fn parses_retry_after_date() {
+        let date = (Utc::now() + TimeDelta::seconds(30)).to_rfc2822();
+        let wait = retry_after(&headers(&date)).unwrap();
+        assert!(
+            wait > Duration::from_secs(25) && wait <= Duration::from_secs(30),
+            "{wait:?}"
+        );
+
+        let past = (Utc::now() - TimeDelta::seconds(30)).to_rfc2822();
+        assert_eq!(retry_after(&headers(&past)), None);
+    }
+
+    #[test]
+    fn ignores_missing_or_invalid_retry_after() {
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&headers("soon")), None);
+    }
+
+    #[test]
+    fn retries_transient_statuses() {
+        for status in [429, 502, 503, 504] {
+            assert!(is_retryable(StatusCode::from_u16(status).unwrap()), "{status}");
+        }
+        for status in [200, 400, 401, 404, 500] {
+            assert!(!is_retryable(StatusCode::from_u16(status).unwrap()), "{status}");
+        }
+    }
+}
@@ -1,23 +1,35 @@
 use std::collections::HashMap;
 use std::env;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use image::guess_format;
 use log::debug;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use serde_json::json;
 
-use crate::cli::Cli;
+use crate::error::{CodedError, ErrorCode};
+use crate::request::GenerationRequest;
+use crate::result::GenerationResult;
+use crate::services::{Model, Service, ServiceKind};
 
-use super::Client;
+use super::retry::{send_with_retry, RetryPolicy};
+use super::{download_client, Client};
 
 const ENV: &str = "TOGETHER_API_KEY";
-const URL: &str = "https://api.together.xyz/v1";
+const DEFAULT_URL: &str = "https://api.together.xyz/v1";
+
+/// FLUX variants that take an input image
+const IMAGE_MODELS: [&str; 4] = ["kontext", "redux", "canny", "depth"];
 
 /// Image response entity
 #[derive(serde::Deserialize, Debug)]
 struct TogetherImage {
     url: String,
+    #[serde(default)]
+    seed: Option<u64>,
 }
 
 /// Response from the Together API
@@ -42,12 +54,39 @@ struct TogetherErrorResponse {
 #[derive(Debug)]
 pub struct TogetherClient {
     pub client: reqwest::Client,
+    /// Downloads result URLs without the API key
+    pub download: reqwest::Client,
+    pub base_url: String,
+    pub timeout: u64,
+    pub retry: RetryPolicy,
+}
+
+/// Size, steps, and guidance are only sent when the model has a default for them
+pub(super) fn supported_parameters(model: &Model) -> Vec<&'static str> {
+    let defaults = [
+        ("width", model.width.is_some()),
+        ("height", model.height.is_some()),
+        ("steps", model.steps.is_some()),
+        ("cfg", model.cfg.is_some()),
+    ];
+    let mut parameters: Vec<&'static str> = defaults
+        .iter()
+        .filter(|(_, supported)| *supported)
+        .map(|(name, _)| *name)
+        .collect();
+    parameters.push("seed");
+    parameters
 }
 
 #[async_trait::async_trait]
 impl Client for TogetherClient {
-    fn new(timeout: u64) -> Result<Self> {
-        let token = env::var(ENV).context(format!("`{ENV}` not set (together.rs)"))?;
+    fn new(
+        service: &Service,
+        timeout: u64,
+        retry: RetryPolicy,
+    ) -> Result<Self> {
+        let token = env::var(ENV)
+            .map_err(|_| CodedError::new(ErrorCode::MissingApiKey, format!("`{ENV}` not set (together.rs)")))?;
         let mut headers = HeaderMap::new();
 
         // https://docs.together.ai/reference/post_images-generations
@@ -64,90 +103,157 @@ impl Client for TogetherClient {
             .build()
             .context("System network error (together.rs)")?;
 
-        Ok(Self { client })
+        let base_url = service.get_base_url(DEFAULT_URL)?;
+        debug!("Using base URL {base_url}");
+
+        Ok(Self {
+            client,
+            download: download_client(timeout, "together.rs")?,
+            base_url,
+            timeout,
+            retry,
+        })
+    }
+
+    /// Together allows up to 4 images per request
+    fn max_batch_size(
+        &self,
+        _model: &Model,
+    ) -> u8 {
+        4
     }
 
-    /// Generate an image using the Together API
-    async fn generate_image(
+    /// Only the FLUX variants built around an input image accept one
+    fn supports_init_image(
         &self,
-        cli: &Cli,
-    ) -> Result<Vec<u8>> {
-        let model = cli.get_model()?;
+        model: &Model,
+    ) -> bool {
+        let name = model.name.to_lowercase();
+        IMAGE_MODELS.iter().any(|m| name.contains(m))
+    }
+
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn kind(&self) -> ServiceKind {
+        ServiceKind::Together
+    }
+
+    /// Generate images using the Together API
+    async fn generate_images(
+        &self,
+        request: &GenerationRequest,
+        n: u8,
+    ) -> Result<Vec<GenerationResult>> {
+        let start = Instant::now();
+        let model = &request.model;
         let mut request_body = HashMap::new();
 
-        let prompt = cli.prompt.as_deref().unwrap().to_string();
         request_body.insert("model".to_string(), json!(model.name));
-        request_body.insert("prompt".to_string(), json!(prompt));
+        request_body.insert("prompt".to_string(), json!(request.prompt));
         request_body.insert("output_format".to_string(), json!("png"));
         request_body.insert("response_format".to_string(), json!("url"));
-        request_body.insert("n".to_string(), json!(1));
+        request_body.insert("n".to_string(), json!(n));
 
+        // Only send parameters the model supports
         if model.width.is_some() {
-            let width = cli.get_width()?;
-            request_body.insert("width".to_string(), json!(width));
+            request_body.insert("width".to_string(), json!(request.get_width()));
         }
 
         if model.height.is_some() {
-            let height = cli.get_height()?;
-            request_body.insert("height".to_string(), json!(height));
+            request_body.insert("height".to_string(), json!(request.get_height()));
         }
 
         if model.steps.is_some() {
-            let steps = cli.get_steps()?;
-            request_body.insert("steps".to_string(), json!(steps));
+            request_body.insert("steps".to_string(), json!(request.get_steps()));
         }
 
         if model.cfg.is_some() {
-            let cfg = cli.get_cfg()?;
-            request_body.insert("guidance".to_string(), json!(cfg));
+            request_body.insert("guidance".to_string(), json!(request.get_cfg()));
         }
 
         // Add seed if preset
-        if let Some(seed) = cli.seed {
+        if let Some(seed) = request.seed {
             request_body.insert("seed".to_string(), json!(seed));
         }
 
         // Add options if present
-        if let Some(options) = &model.options {
-            for (key, value) in options {
-                request_body.insert(key.clone(), value.clone());
-            }
+        for (key, value) in request.get_options() {
+            request_body.insert(key, value);
+        }
+
+        // The input image is sent inline as a data URL and kept out of the recorded parameters
+        let mut body = request_body.clone();
+        if let Some(image) = request.read_init_image()? {
+            let mime_type = guess_format(&image)?.to_mime_type();
+            let data_url = format!("data:{mime_type};base64,{}", STANDARD.encode(image));
+            body.insert("image_url".to_string(), json!(data_url));
         }
 
         debug!("Sending request to Together API");
-        let image_url = format!("{URL}/images/generations");
-        let response = match self.client.post(image_url).json(&request_body).send().await {
-            Ok(response) => response,
-            Err(e) if e.is_timeout() => {
-                bail!("Request timed out after {} seconds (together.rs)", cli.timeout)
-            }
-            Err(e) => {
-                bail!("{e} (together.rs)")
-            }
-        };
+        let image_url = format!("{}/images/generations", self.base_url);
+        let response = send_with_retry(
+            self.client.post(image_url).json(&body),
+            &self.retry,
+            self.timeout,
+            "together.rs",
+        )
+        .await?;
 
         // Handle the response
         if response.status().is_success() {
             let together_response: TogetherResponse = response.json().await?;
 
             debug!("Parsing first response from Together API");
-            let response_image_url = together_response.data.first().unwrap().url.clone();
-
-            debug!("Fetching image result");
-            let response_image = self.client.get(response_image_url).send().await?;
-
-            if response_image.status().is_success() {
-                debug!("Parsing second response from Together API");
-                let bytes = response_image.bytes().await?;
-                Ok(bytes.to_vec())
-            } else {
-                // Error fetching image after successful generation
-                bail!("Failed to fetch image after successful generation (together.rs)")
+            if together_response.data.is_empty() {
+                bail!(CodedError::new(
+                    ErrorCode::Provider,
+                    "No image data found in response (together.rs)"
+                ))
+            }
+
+            let mut results = Vec::with_capacity(together_response.data.len());
+            for image in together_response.data {
+                debug!("Fetching image result");
+                let response_image =
+                    send_with_retry(self.download.get(&image.url), &self.retry, self.timeout, "together.rs").await?;
+
+                if response_image.status().is_success() {
+                    debug!("Parsing second response from Together API");
+                    let content_type = response_image
+                        .headers()
+                        .get(CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string);
+                    let bytes = response_image.bytes().await?;
+
+                    let mut result = GenerationResult::new(
+                        bytes.to_vec(),
+                        content_type.as_deref(),
+                        request,
+                        &request_body,
+                        start.elapsed(),
+                    );
+                    result.seed = image.seed.or(request.seed);
+                    result.url = Some(image.url);
+                    results.push(result);
+                } else {
+                    // Error fetching image after successful generation
+                    bail!(CodedError::new(
+                        ErrorCode::Provider,
+                        "Failed to fetch image after successful generation (together.rs)"
+                    ))
+                }
             }
+            Ok(results)
         } else {
             // Error generating image
             let error_response: TogetherErrorResponse = response.json().await?;
-            bail!("{} (together.rs)", error_response.error.message)
+            bail!(CodedError::new(
+                ErrorCode::Provider,
+                format!("{} (together.rs)", error_response.error.message)
+            ))
         }
     }
 }
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CodedError, ErrorCode};
+use crate::services::{DefaultModel, DefaultService, Model, ResponseFormat, ServiceId, ServiceKind};
+
+/// Schema for a service override in the user configuration
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub default: Option<DefaultModel>,
+    pub base_url: Option<String>,
+    #[serde(default)]
+    pub models: Vec<Model>,
+}
+
+/// Schema for a user-defined service (e.g., an OpenAI-compatible server)
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomServiceConfig {
+    #[serde(rename = "type")]
+    pub kind: ServiceKind,
+    pub base_url: String,
+    /// Environment variable with the API key (none is sent if unset)
+    pub api_key_env: Option<String>,
+    pub response_format: Option<ResponseFormat>,
+    pub default: Option<DefaultModel>,
+    #[serde(default)]
+    pub models: Vec<Model>,
+}
+
+/// Schema for the local result cache in the user configuration
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Reuse images for requests with a fixed seed (off by default)
+    #[serde(default)]
+    pub enabled: bool,
+    /// Size limit in MiB (1024 by default)
+    pub max_size: Option<u64>,
+}
+
+/// User configuration merged over the built-in services
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub default: Option<DefaultService>,
+    pub hf: Option<ServiceConfig>,
+    pub openai: Option<ServiceConfig>,
+    pub together: Option<ServiceConfig>,
+    pub a1111: Option<ServiceConfig>,
+    /// User-defined services by name
+    #[serde(default)]
+    pub services: BTreeMap<ServiceId, CustomServiceConfig>,
+    pub cache: Option<CacheConfig>,
+}
+
+impl Config {
+    /// Parse a TOML or JSON configuration file based on its extension
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let display = path.display();
+        let contents = fs::read_to_string(path).context(format!("Failed to read `{display}` (config.rs)"))?;
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("toml");
+
+        debug!("Parsing config {display}");
+        let mut config: Self = match ext {
+            "toml" => toml::from_str(&contents).map_err(|e| {
+                CodedError::new(
+                    ErrorCode::Config,
+                    format!("Invalid config `{display}`: {e} (config.rs)"),
+                )
+            })?,
+            "json" => serde_json::from_str(&contents).map_err(|e| {
+                CodedError::new(
+                    ErrorCode::Config,
+                    format!("Invalid config `{display}`: {e} (config.rs)"),
+                )
+            })?,
+            _ => bail!(CodedError::new(
+                ErrorCode::Config,
+                format!("Unsupported config format `{ext}` (config.rs)")
+            )),
+        };
+
+        // Resolve workflow paths relative to the config file so it can be loaded from anywhere
+        if let Some(dir) = path.parent() {
+            let workflows = config
+                .services
+                .values_mut()
+                .flat_map(|service| service.models.iter_mut())
+                .filter_map(|model| model.workflow.as_mut());
+            for workflow in workflows {
+                workflow.path = dir.join(&workflow.path);
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Load the config from the given path or the default location
+    /// An explicit path must exist, but a missing default config is ignored
+    pub fn load(path: Option<&Path>) -> Result<Option<Self>> {
+        if let Some(path) = path {
+            return Self::from_file(path).map(Some);
+        }
+
+        match default_config_path() {
+            Some(path) if path.is_file() => Self::from_file(&path).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Get the default config path (`$XDG_CONFIG_HOME/txt2img/config.toml`)
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("txt2img").join("config.toml"))
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+use std::io;
+
+use serde::Serialize;
+use strum::Display;
+
+/// Stable error codes for machine-readable output
+#[derive(Clone, Copy, Debug, Display, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ErrorCode {
+    BatchFailed,
+    Cancelled,
+    Config,
+    InvalidRequest,
+    MissingApiKey,
+    Network,
+    Timeout,
+    Provider,
+    Io,
+    Internal,
+}
+
+/// An error with a stable code
+#[derive(Debug)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CodedError {
+    pub fn new(
+        code: ErrorCode,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// Get the code for an error, falling back to well-known error types in the chain
+pub fn error_code(error: &anyhow::Error) -> ErrorCode {
+    if let Some(coded) = error.chain().find_map(|e| e.downcast_ref::<CodedError>()) {
+        return coded.code;
+    }
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return if e.is_timeout() {
+                ErrorCode::Timeout
+            } else if e.is_decode() {
+                ErrorCode::Provider
+            } else {
+                ErrorCode::Network
+            };
+        }
+        if cause.is::<io::Error>() {
+            return ErrorCode::Io;
+        }
+    }
+    ErrorCode::Internal
+}
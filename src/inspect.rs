@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use image::ImageFormat;
+use serde::Serialize;
+
+use crate::metadata::{read_parameters, ImageMetadata};
+
+/// Generation parameters read back from an image
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    pub path: String,
+    /// Raw `parameters` text
+    pub parameters: String,
+    pub metadata: ImageMetadata,
+}
+
+/// Read the embedded generation parameters from an image file
+pub fn inspect_image(path: &Path) -> Result<Inspection> {
+    let display = path.display();
+    let bytes = fs::read(path).context(format!("Failed to read `{display}` (inspect.rs)"))?;
+    let parameters = read_parameters(&bytes)
+        .map_err(|e| anyhow!("`{display}`: {e}"))?
+        .context(format!("No generation parameters in `{display}` (inspect.rs)"))?;
+
+    Ok(Inspection {
+        path: display.to_string(),
+        metadata: ImageMetadata::from_parameters(&parameters),
+        parameters,
+    })
+}
+
+/// Get the output path for a rerun (e.g., `out/image.png` to `out/image-rerun.png`)
+/// The extension follows the format if one is given, and the path is used as is rather than as a template
+pub fn rerun_path(
+    path: &Path,
+    format: Option<ImageFormat>,
+) -> String {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+    let ext = match format {
+        Some(format) => format.extensions_str()[0],
+        None => path.extension().and_then(|e| e.to_str()).unwrap_or("png"),
+    };
+    path.with_file_name(format!("{stem}-rerun.{ext}"))
+        .to_string_lossy()
+        .into_owned()
+}
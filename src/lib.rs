@@ -1,10 +1,45 @@
+mod batch;
+mod cache;
+mod capabilities;
 mod cli;
 mod client;
+mod config;
+mod error;
+mod inspect;
+mod mask;
+mod metadata;
+mod output;
+mod preview;
+mod request;
+mod result;
 mod services;
+mod sidecar;
+mod template;
 mod utils;
 
 // Used in main
-pub use cli::Cli;
-pub use client::create_client;
-pub use services::get_or_init_services;
-pub use utils::{create_progress_bar, init_logger, write_image};
+pub use batch::{read_prompts_file, run_batch, BatchFailure, BatchSuccess, BatchSummary, PromptEntry};
+pub use cache::{Cache, CacheEntry};
+pub use capabilities::{AspectRatio, Capabilities, Size};
+pub use cli::{CacheArgs, CacheCommand, Cli, Command, InspectArgs};
+pub use client::{check_ignored_parameters, create_client, generate_batch, supported_parameters, Client, RetryPolicy};
+pub use config::{CacheConfig, Config};
+pub use error::{error_code, CodedError, ErrorCode};
+pub use inspect::{inspect_image, rerun_path, Inspection};
+pub use mask::Mask;
+pub use metadata::{embed_metadata, read_parameters, ImageMetadata};
+pub use output::{
+    print_json, ErrorOutput, GenerationOutput, ImageFileFormat, ModelOutput, OutputFormat, ServiceOutput,
+};
+pub use preview::{preview_image, Protocol};
+pub use request::{GenerationRequest, GenerationRequestBuilder};
+pub use result::GenerationResult;
+pub use services::{
+    get_or_init_services, init_services, Model, ModelId, OpenAIImageBackground, OpenAIImageQuality, OpenAIImageStyle,
+    OpenAIModeration, ResponseFormat, Service, ServiceId, ServiceKind, Services,
+};
+pub use sidecar::{write_sidecar, Sidecar};
+pub use template::{render_path, slugify};
+pub use utils::{
+    check_path_format, create_progress_bar, init_logger, numbered_path, write_image, write_images, STDOUT,
+};
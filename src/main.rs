@@ -1,25 +1,70 @@
+use std::path::Path;
 use std::time::Instant;
 
 use anyhow::{bail, Result};
 use clap::Parser;
 use colored::Colorize;
+use indicatif::MultiProgress;
 use log::{debug, error};
 use tokio::select;
 
-use txt2img::{create_client, create_progress_bar, init_logger, write_image, Cli};
+use txt2img::{
+    check_ignored_parameters, check_path_format, create_client, create_progress_bar, error_code, generate_batch,
+    get_or_init_services, init_logger, init_services, inspect_image, preview_image, print_json, read_prompts_file,
+    rerun_path, run_batch, write_image, write_images, write_sidecar, Cache, CacheArgs, CacheCommand, Cli, CodedError,
+    Command, Config, ErrorCode, ErrorOutput, GenerationOutput, ImageMetadata, InspectArgs, ModelOutput, OutputFormat,
+    ServiceOutput, STDOUT,
+};
 
-async fn run() -> Result<()> {
+async fn run(cli: &Cli) -> Result<()> {
     // Start timer
     let start = Instant::now();
 
-    // Parse command line arguments
-    let cli = Cli::parse();
-
     // Initialize logger
-    let multi_progress = init_logger(cli.debug)?;
+    let multi_progress = init_logger(cli.debug, cli.is_stdout_reserved())?;
+    let json = cli.output == OutputFormat::Json;
+    let to_stdout = cli.out == STDOUT;
+    if to_stdout && json {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            "`--output json` cannot be used when writing the image to stdout"
+        ));
+    }
+
+    // Load user config over the built-in services
+    let config = Config::load(cli.config.as_deref())?;
+    let cache_config = config.as_ref().and_then(|c| c.cache.clone()).unwrap_or_default();
+    init_services(config)?;
+
+    // Handle subcommands
+    match &cli.command {
+        Some(Command::Inspect(args)) => return inspect(cli, args, &multi_progress).await,
+        Some(Command::Cache(args)) => return cache(cli, args, &Cache::new(&cache_config)?),
+        None => {}
+    }
+
+    // The cache is opt-in since it returns the same image for the same seed and parameters
+    let cache = if cache_config.enabled && !cli.no_cache {
+        Some(Cache::new(&cache_config)?)
+    } else {
+        None
+    };
 
     // Handle list services flag
     if cli.list_services {
+        if json {
+            let services = get_or_init_services();
+            let output: Vec<_> = services
+                .iter()
+                .map(|service| ServiceOutput {
+                    id: &service.id,
+                    kind: service.kind,
+                    default: service.id == services.default.id,
+                    default_model: &service.default.id,
+                })
+                .collect();
+            return print_json(&output);
+        }
         for service in cli.get_services()? {
             println!("{service}");
         }
@@ -28,8 +73,15 @@ async fn run() -> Result<()> {
 
     // Handle list models flag
     if cli.list_models {
-        for model in cli.get_models()? {
-            println!("{}", model.id);
+        let models = cli.get_models()?;
+        if json {
+            let service = cli.get_service_config()?;
+            let output: Vec<_> = models.iter().map(|m| ModelOutput::new(m, service)).collect();
+            return print_json(&output);
+        }
+        let width = models.iter().map(|m| m.id.0.len()).max().unwrap_or(0);
+        for model in models {
+            println!("{:<width$}  {}", model.id, model.name);
         }
         return Ok(());
     }
@@ -41,24 +93,85 @@ async fn run() -> Result<()> {
     // Create progress bar and start it
     let pb = create_progress_bar(cli.quiet, &multi_progress);
 
-    // Create client
-    let service = cli.get_service()?;
-    let client = create_client(service, &cli.timeout)?;
+    // Handle prompts file
+    if let Some(prompts_file) = &cli.prompts_file {
+        if to_stdout {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                "`--prompts-file` cannot be used when writing images to stdout"
+            ));
+        }
+        let entries = read_prompts_file(prompts_file)?;
+        if let Some(pb) = &pb {
+            pb.set_message(format!("Generating images (0/{})", entries.len()));
+        }
+
+        let mut summary = select! {
+            biased;
+            _ = &mut shutdown => {
+                if let Some(pb) = pb { pb.finish_and_clear(); }
+                bail!(CodedError::new(ErrorCode::Cancelled, "Operation cancelled by user"));
+            },
+            summary = run_batch(cli, entries, cache, pb.clone()) => summary,
+        };
+
+        let failed = (!summary.failed.is_empty()).then(|| {
+            anyhow::Error::from(CodedError::new(
+                ErrorCode::BatchFailed,
+                format!("{} of {} prompts failed", summary.failed.len(), summary.total()),
+            ))
+        });
+        if json {
+            summary.error = failed.as_ref().map(|e| ErrorOutput::from(e).error);
+            print_json(&summary)?;
+        }
+
+        let stop = format!("{:.2}", start.elapsed().as_secs_f32());
+        let message = format!(
+            "Generated {} of {} prompts in {}s",
+            summary.succeeded.len().to_string().blue(),
+            summary.total().to_string().blue(),
+            stop.blue()
+        );
+        match pb {
+            Some(pb) => pb.finish_with_message(message),
+            None if !summary.failed.is_empty() => eprintln!("{message}"),
+            None => {}
+        }
+
+        for failure in &summary.failed {
+            eprintln!("  {} line {}: {}", "✗".red(), failure.line, failure.message);
+        }
+        return match failed {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+
+    // Create request and client
+    check_path_format(&cli.out, cli.get_format())?;
+    let request = cli.to_request()?;
+    let client = create_client(&request.service, &cli.timeout, cli.get_retry_policy())?;
+    check_ignored_parameters(client.as_ref(), &request, cli.strict)?;
 
     // Update progress
     if let Some(pb) = &pb {
-        pb.set_message("Generating image");
+        if cli.count > 1 {
+            pb.set_message(format!("Generating {} images", cli.count));
+        } else {
+            pb.set_message("Generating image");
+        }
     }
 
-    // Generate image
-    let image_bytes = select! {
+    // Generate images
+    let results = select! {
         // Start the block with `biased` to poll futures from top to bottom
         biased;
         _ = &mut shutdown => {
             if let Some(pb) = pb { pb.finish_and_clear(); }
-            bail!("Operation cancelled by user");
+            bail!(CodedError::new(ErrorCode::Cancelled, "Operation cancelled by user"));
         },
-        result = client.generate_image(&cli) => result?,
+        result = generate_batch(client.as_ref(), &request, cli.count, cache.as_ref()) => result?,
     };
 
     // Update progress
@@ -66,24 +179,198 @@ async fn run() -> Result<()> {
         pb.set_message("Saving image");
     }
 
-    // Save
-    let file_path = write_image(&cli.out, &image_bytes)?;
+    // Save (numbered when generating multiple images)
+    let file_paths = write_images(&cli.out, &results, cli.get_format(), !cli.no_metadata, cli.no_clobber)?;
+    for (file_path, result) in file_paths.iter().zip(&results) {
+        // There is no file to put a sidecar next to when streaming
+        if cli.sidecar && !to_stdout {
+            write_sidecar(file_path, result, start.elapsed())?;
+        }
+        debug!(
+            "{file_path}: {} {} seed={:?} {} in {:.2}s",
+            result.request.service,
+            result.request.model.name,
+            result.seed,
+            result.mime_type,
+            result.elapsed.as_secs_f32()
+        );
+    }
+
+    if json {
+        let output = GenerationOutput::new(&file_paths, &results, start.elapsed().as_secs_f64());
+        print_json(&output)?;
+    }
 
     // Take ownership of progress bar and stop it
     if let Some(pb) = pb {
         debug!("Stopping progress bar");
         let stop = format!("{:.2}", start.elapsed().as_secs_f32());
-        let message = format!("Generated {} in {}s", file_path.blue(), stop.blue()).to_string();
+        let files = file_paths
+            .iter()
+            .map(|f| if f == STDOUT { "stdout" } else { f }.blue().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let message = format!("Generated {} in {}s", files, stop.blue()).to_string();
         pb.finish_with_message(message);
     }
 
+    if cli.preview {
+        for result in &results {
+            preview_image(&result.image)?;
+        }
+    }
+
+    if !cli.quiet {
+        // Surface prompts rewritten by the provider so the output isn't a surprise
+        for result in &results {
+            if let Some(revised_prompt) = &result.revised_prompt {
+                eprintln!("{} {revised_prompt}", "Revised prompt:".bold());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the embedded parameters of each image and optionally regenerate it
+async fn inspect(
+    cli: &Cli,
+    args: &InspectArgs,
+    multi_progress: &MultiProgress,
+) -> Result<()> {
+    let mut inspections = Vec::with_capacity(args.paths.len());
+    for path in &args.paths {
+        match inspect_image(path) {
+            Ok(inspection) => inspections.push(inspection),
+            Err(e) => error!("{e}"),
+        }
+    }
+
+    if cli.output == OutputFormat::Text {
+        for inspection in &inspections {
+            println!("{}\n{}\n", inspection.path.bold(), inspection.parameters);
+        }
+    }
+
+    if args.rerun {
+        for inspection in &inspections {
+            let start = Instant::now();
+            let pb = create_progress_bar(cli.quiet, multi_progress);
+            if let Some(pb) = &pb {
+                pb.set_message(format!("Regenerating {}", inspection.path));
+            }
+
+            let request = inspection
+                .metadata
+                .to_request(cli.service.as_ref(), cli.model.as_ref())?;
+            let client = create_client(&request.service, &cli.timeout, cli.get_retry_policy())?;
+            check_ignored_parameters(client.as_ref(), &request, cli.strict)?;
+            // Regenerating is the point, so the cache isn't used
+            let results = generate_batch(client.as_ref(), &request, 1, None).await?;
+            let out = rerun_path(Path::new(&inspection.path), cli.get_format());
+            // Written directly since braces in the original filename aren't placeholders
+            let mut file_paths = Vec::new();
+            for result in &results {
+                let metadata = (!cli.no_metadata).then(|| ImageMetadata::from(result));
+                let file_path = write_image(&out, &result.image, cli.get_format(), metadata.as_ref(), cli.no_clobber)?;
+                if cli.sidecar {
+                    write_sidecar(&file_path, result, start.elapsed())?;
+                }
+                file_paths.push(file_path);
+            }
+
+            if let Some(pb) = pb {
+                let stop = format!("{:.2}", start.elapsed().as_secs_f32());
+                pb.finish_with_message(format!(
+                    "Generated {} in {}s",
+                    file_paths.join(", ").blue(),
+                    stop.blue()
+                ));
+            }
+        }
+    }
+
+    if inspections.len() < args.paths.len() {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "{} of {} images have no parameters",
+                args.paths.len() - inspections.len(),
+                args.paths.len()
+            )
+        ));
+    }
+    // Printed last so a failure leaves a single JSON document on stdout
+    if cli.output == OutputFormat::Json {
+        print_json(&inspections)?;
+    }
+    Ok(())
+}
+
+/// List or clear the cached images
+fn cache(
+    cli: &Cli,
+    args: &CacheArgs,
+    cache: &Cache,
+) -> Result<()> {
+    match args.command {
+        CacheCommand::Ls => {
+            let entries = cache.entries()?;
+            if cli.output == OutputFormat::Json {
+                return print_json(&entries);
+            }
+            for entry in &entries {
+                println!(
+                    "{}  {:>7.1} KiB  {}  {}  {}  seed={}  {}",
+                    entry.key.get(..12).unwrap_or(&entry.key),
+                    entry.size as f64 / 1024.0,
+                    entry.created_at,
+                    entry.service,
+                    entry.model,
+                    entry.seed.map_or_else(|| "-".to_string(), |s| s.to_string()),
+                    entry.prompt
+                );
+            }
+            if !cli.quiet {
+                eprintln!(
+                    "{} entries, {:.1} of {} MiB in {}",
+                    entries.len(),
+                    cache.size()? as f64 / (1024.0 * 1024.0),
+                    cache.max_size / (1024 * 1024),
+                    cache.dir.display()
+                );
+            }
+        }
+        CacheCommand::Clear => {
+            let removed = cache.clear()?;
+            if cli.output == OutputFormat::Json {
+                return print_json(&serde_json::json!({ "removed": removed }));
+            }
+            if !cli.quiet {
+                eprintln!("Removed {removed} cached images from {}", cache.dir.display());
+            }
+        }
+    }
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    if let Err(e) = run().await {
-        error!("{} (main.rs)", e);
+    // Parse command line arguments
+    let cli = Cli::parse();
+
+    if let Err(e) = run(&cli).await {
+        match cli.output {
+            // The batch summary already includes the error, so stdout stays a single document
+            OutputFormat::Json if error_code(&e) == ErrorCode::BatchFailed => {}
+            OutputFormat::Json => {
+                // Fall back to the logger if serialization fails
+                if print_json(&ErrorOutput::from(&e)).is_err() {
+                    error!("{} (main.rs)", e);
+                }
+            }
+            OutputFormat::Text => error!("{} (main.rs)", e),
+        }
         std::process::exit(1);
     }
 }
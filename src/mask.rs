@@ -0,0 +1,83 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use image::imageops::{self, FilterType};
+use image::{guess_format, load_from_memory, GrayImage, ImageFormat, ImageReader, Luma, RgbaImage};
+use log::debug;
+
+use crate::error::{CodedError, ErrorCode};
+
+/// Inpainting mask where transparent pixels mark the area to edit (the OpenAI convention)
+#[derive(Clone, Debug)]
+pub struct Mask(RgbaImage);
+
+impl Mask {
+    /// Read a PNG mask with an alpha channel and resize it to the init image
+    pub fn read(
+        path: &Path,
+        init_image: &[u8],
+    ) -> Result<Self> {
+        let display = path.display();
+        let bytes = fs::read(path).context(format!("Failed to read mask `{display}` (mask.rs)"))?;
+
+        // JPEG and friends have no alpha channel to mark the edit area with
+        if guess_format(&bytes).ok() != Some(ImageFormat::Png) {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!("Mask `{display}` must be a PNG (mask.rs)")
+            ));
+        }
+        let decoded = load_from_memory(&bytes).context(format!("Failed to decode mask `{display}` (mask.rs)"))?;
+        if !decoded.color().has_alpha() {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!("Mask `{display}` must be RGBA with transparent areas to edit (mask.rs)")
+            ));
+        }
+
+        let mut mask = decoded.to_rgba8();
+        if !mask.pixels().any(|p| p[3] == 0) {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!("Mask `{display}` has no transparent areas to edit (mask.rs)")
+            ));
+        }
+
+        // Providers reject masks that don't match the image, so scale without blending the edges
+        let (width, height) = ImageReader::new(Cursor::new(init_image))
+            .with_guessed_format()?
+            .into_dimensions()
+            .context("Failed to read init image size (mask.rs)")?;
+        if mask.dimensions() != (width, height) {
+            debug!(
+                "Resizing mask from {}x{} to {width}x{height}",
+                mask.width(),
+                mask.height()
+            );
+            mask = imageops::resize(&mask, width, height, FilterType::Nearest);
+        }
+        Ok(Self(mask))
+    }
+
+    /// Encode as an RGBA PNG (OpenAI and ComfyUI)
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let mut png = Cursor::new(Vec::new());
+        self.0
+            .write_to(&mut png, ImageFormat::Png)
+            .context("Failed to encode mask (mask.rs)")?;
+        Ok(png.into_inner())
+    }
+
+    /// Encode as a grayscale PNG where white marks the area to edit (AUTOMATIC1111)
+    pub fn to_grayscale_png(&self) -> Result<Vec<u8>> {
+        let gray = GrayImage::from_fn(self.0.width(), self.0.height(), |x, y| {
+            Luma([255 - self.0.get_pixel(x, y)[3]])
+        });
+        let mut png = Cursor::new(Vec::new());
+        gray.write_to(&mut png, ImageFormat::Png)
+            .context("Failed to encode mask (mask.rs)")?;
+        Ok(png.into_inner())
+    }
+}
@@ -0,0 +1,966 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use image::{guess_format, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CodedError, ErrorCode};
+use crate::request::GenerationRequest;
+use crate::result::GenerationResult;
+use crate::services::{ModelId, OpenAIImageStyle, ServiceId};
+
+/// Key used by AUTOMATIC1111 and other tools for generation parameters
+pub const PARAMETERS_KEY: &str = "parameters";
+
+const SOFTWARE: &str = concat!("txt2img ", env!("CARGO_PKG_VERSION"));
+const XMP_NAMESPACE: &str = "https://github.com/adamelliotfields/txt2img";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Generation parameters embedded in output images
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ImageMetadata {
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    pub steps: Option<u8>,
+    pub cfg: Option<f32>,
+    pub seed: Option<u64>,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub style: Option<String>,
+    pub sampler: Option<String>,
+    /// Denoising strength for image-to-image
+    pub strength: Option<f32>,
+    /// Upstream model name
+    pub model: Option<String>,
+    pub service: Option<ServiceId>,
+    pub version: Option<String>,
+    /// Fields from other tools (e.g., `Clip skip` from AUTOMATIC1111)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
+}
+
+impl From<&GenerationResult> for ImageMetadata {
+    fn from(result: &GenerationResult) -> Self {
+        let request = &result.request;
+        let model = &request.model;
+        Self {
+            prompt: request.prompt.clone(),
+            negative_prompt: request.get_negative_prompt().map(str::to_string),
+            steps: request.get_steps(),
+            cfg: request.get_cfg(),
+            seed: result.seed,
+            width: request.get_width(),
+            height: request.get_height(),
+            // Style is only sent to models that support it
+            style: model.style.as_ref().and(request.get_style()).map(|s| s.to_string()),
+            sampler: request.get_sampler().map(str::to_string),
+            strength: request.strength,
+            // AUTOMATIC1111 models without a name use the loaded checkpoint
+            model: Some(model.name.clone()).filter(|name| !name.is_empty()),
+            service: Some(request.service.clone()),
+            version: Some(SOFTWARE.to_string()),
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+impl ImageMetadata {
+    /// Format as AUTOMATIC1111-style `parameters` text
+    pub fn to_parameters(&self) -> String {
+        let mut text = self.prompt.clone();
+        if let Some(negative_prompt) = &self.negative_prompt {
+            text.push_str(&format!("\nNegative prompt: {negative_prompt}"));
+        }
+
+        let mut fields = Vec::new();
+        if let Some(steps) = self.steps {
+            fields.push(format!("Steps: {steps}"));
+        }
+        if let Some(sampler) = &self.sampler {
+            fields.push(format!("Sampler: {sampler}"));
+        }
+        if let Some(cfg) = self.cfg {
+            fields.push(format!("CFG scale: {cfg}"));
+        }
+        if let Some(seed) = self.seed {
+            fields.push(format!("Seed: {seed}"));
+        }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            fields.push(format!("Size: {width}x{height}"));
+        }
+        if let Some(strength) = self.strength {
+            fields.push(format!("Denoising strength: {strength}"));
+        }
+        if let Some(style) = &self.style {
+            fields.push(format!("Style: {style}"));
+        }
+        if let Some(model) = &self.model {
+            fields.push(format!("Model: {model}"));
+        }
+        if let Some(service) = &self.service {
+            fields.push(format!("Service: {service}"));
+        }
+        if let Some(version) = &self.version {
+            fields.push(format!("Version: {version}"));
+        }
+        for (key, value) in &self.extra {
+            fields.push(format!("{key}: {value}"));
+        }
+
+        if !fields.is_empty() {
+            text.push('\n');
+            text.push_str(&fields.join(", "));
+        }
+        text
+    }
+}
+
+/// Known keys used to tell the fields line apart from the prompt
+const FIELD_KEYS: [&str; 8] = [
+    "Steps",
+    "Sampler",
+    "CFG scale",
+    "Seed",
+    "Size",
+    "Model",
+    "Service",
+    "Version",
+];
+
+/// Split an AUTOMATIC1111 fields line (`Key: value, Key: value`) into pairs
+/// Commas inside values are kept by joining tokens that don't start with a key
+fn parse_fields(line: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for token in line.split(", ") {
+        let key_value = token.split_once(": ").filter(|(key, _)| {
+            key.starts_with(|c: char| c.is_ascii_uppercase())
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-')
+        });
+        match (key_value, fields.last_mut()) {
+            (Some((key, value)), _) => fields.push((key.to_string(), value.to_string())),
+            (None, Some((_, value))) => {
+                value.push_str(", ");
+                value.push_str(token);
+            }
+            (None, None) => return Vec::new(),
+        }
+    }
+    fields
+}
+
+impl ImageMetadata {
+    /// Parse AUTOMATIC1111-style `parameters` text
+    pub fn from_parameters(text: &str) -> Self {
+        let mut lines: Vec<&str> = text.lines().collect();
+        let mut metadata = Self::default();
+
+        // The last line holds the fields if it has any known key
+        let fields = lines
+            .last()
+            .map(|line| parse_fields(line))
+            .filter(|fields| fields.iter().any(|(key, _)| FIELD_KEYS.contains(&key.as_str())));
+        if fields.is_some() {
+            lines.pop();
+        }
+
+        // Everything after `Negative prompt:` is the negative prompt
+        let negative_index = lines.iter().position(|line| line.starts_with("Negative prompt: "));
+        if let Some(index) = negative_index {
+            let negative_prompt = lines[index..].join("\n");
+            metadata.negative_prompt = Some(negative_prompt["Negative prompt: ".len()..].to_string());
+            lines.truncate(index);
+        }
+        metadata.prompt = lines.join("\n");
+
+        for (key, value) in fields.unwrap_or_default() {
+            match key.as_str() {
+                "Steps" => metadata.steps = value.parse().ok(),
+                "CFG scale" => metadata.cfg = value.parse().ok(),
+                "Seed" => metadata.seed = value.parse().ok(),
+                "Size" => {
+                    if let Some((width, height)) = value.split_once('x') {
+                        metadata.width = width.parse().ok();
+                        metadata.height = height.parse().ok();
+                    }
+                }
+                "Sampler" => metadata.sampler = Some(value),
+                "Denoising strength" => metadata.strength = value.parse().ok(),
+                "Style" => metadata.style = Some(value),
+                "Model" => metadata.model = Some(value),
+                "Service" => metadata.service = serde_json::from_value(serde_json::Value::String(value)).ok(),
+                "Version" => metadata.version = Some(value),
+                _ => {
+                    metadata.extra.insert(key, value);
+                }
+            }
+        }
+        metadata
+    }
+}
+
+impl ImageMetadata {
+    /// Build a request that reproduces the image, with the given service and model used only if it doesn't record them
+    /// The init image isn't embedded, so image-to-image results are rerun as text-to-image
+    pub fn to_request(
+        &self,
+        service: Option<&ServiceId>,
+        model: Option<&ModelId>,
+    ) -> Result<GenerationRequest> {
+        let Some(service) = self.service.as_ref().or(service) else {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                "The image doesn't record its service, pass --service to rerun it (metadata.rs)"
+            ));
+        };
+        let Some(model) = self.model.as_deref().map(ModelId::from).or_else(|| model.cloned()) else {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                "The image doesn't record its model, pass --model to rerun it (metadata.rs)"
+            ));
+        };
+        let mut builder = GenerationRequest::builder(&self.prompt)
+            .service(service.clone())
+            .model(model);
+        if let Some(negative_prompt) = &self.negative_prompt {
+            builder = builder.negative_prompt(negative_prompt);
+        }
+        if let Some(steps) = self.steps {
+            builder = builder.steps(steps);
+        }
+        if let Some(cfg) = self.cfg {
+            builder = builder.cfg(cfg);
+        }
+        if let Some(seed) = self.seed {
+            builder = builder.seed(seed);
+        }
+        if let Some(width) = self.width {
+            builder = builder.width(width);
+        }
+        if let Some(height) = self.height {
+            builder = builder.height(height);
+        }
+        if let Some(style) = &self.style {
+            let style = OpenAIImageStyle::from_str(style, true).map_err(|e| anyhow!("{e} (metadata.rs)"))?;
+            builder = builder.style(style);
+        }
+        if let Some(sampler) = &self.sampler {
+            builder = builder.sampler(sampler);
+        }
+        builder.build()
+    }
+}
+
+/// Embed metadata into encoded image bytes
+pub fn embed_metadata(
+    bytes: Vec<u8>,
+    format: ImageFormat,
+    metadata: &ImageMetadata,
+) -> Result<Vec<u8>> {
+    let parameters = metadata.to_parameters();
+    match format {
+        ImageFormat::Png => embed_png(bytes, &parameters),
+        ImageFormat::Jpeg => embed_jpeg(bytes, &parameters),
+        ImageFormat::WebP => embed_webp(bytes, &parameters),
+        _ => bail!("Metadata not supported for {format:?} (metadata.rs)"),
+    }
+}
+
+/// Build a PNG chunk (length, type, data, CRC)
+fn png_chunk(
+    kind: &[u8; 4],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+    chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+    chunk
+}
+
+/// Build a tEXt chunk for Latin-1 text or an uncompressed iTXt chunk for UTF-8
+fn png_text_chunk(
+    key: &str,
+    text: &str,
+) -> Vec<u8> {
+    let mut data = key.as_bytes().to_vec();
+    data.push(0);
+    if text.chars().all(|c| (c as u32) < 0x100) {
+        data.extend(text.chars().map(|c| c as u8));
+        png_chunk(b"tEXt", &data)
+    } else {
+        // Compression flag, compression method, empty language tag, empty translated keyword
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(text.as_bytes());
+        png_chunk(b"iTXt", &data)
+    }
+}
+
+/// Insert text chunks before IEND
+fn embed_png(
+    bytes: Vec<u8>,
+    parameters: &str,
+) -> Result<Vec<u8>> {
+    // IEND is always the last 12 bytes of a PNG
+    if bytes.len() < 20 || &bytes[bytes.len() - 8..bytes.len() - 4] != b"IEND" {
+        bail!("Invalid PNG (metadata.rs)");
+    }
+
+    let iend = bytes.len() - 12;
+    let mut out = Vec::with_capacity(bytes.len() + parameters.len() + 64);
+    out.extend_from_slice(&bytes[..iend]);
+    out.extend(png_text_chunk(PARAMETERS_KEY, parameters));
+    out.extend(png_text_chunk("Software", SOFTWARE));
+    out.extend_from_slice(&bytes[iend..]);
+    Ok(out)
+}
+
+/// Build a big-endian TIFF structure with Software and an Exif UserComment
+/// UserComment uses the `UNICODE` character code (UCS-2 in the TIFF byte order) like AUTOMATIC1111
+fn exif_payload(parameters: &str) -> Vec<u8> {
+    let mut software = SOFTWARE.as_bytes().to_vec();
+    software.push(0);
+    if software.len() % 2 == 1 {
+        software.push(0);
+    }
+
+    let mut comment = b"UNICODE\0".to_vec();
+    comment.extend(parameters.encode_utf16().flat_map(u16::to_be_bytes));
+
+    // Header (8) + IFD0 with 2 entries (2 + 24 + 4) + software + Exif IFD with 1 entry (2 + 12 + 4) + comment
+    let ifd0_offset = 8u32;
+    let software_offset = ifd0_offset + 2 + 2 * 12 + 4;
+    let exif_ifd_offset = software_offset + software.len() as u32;
+    let comment_offset = exif_ifd_offset + 2 + 12 + 4;
+
+    let mut tiff = b"MM\0\x2a".to_vec();
+    tiff.extend_from_slice(&ifd0_offset.to_be_bytes());
+
+    // IFD0: Software (ASCII) and ExifIFDPointer (LONG)
+    tiff.extend_from_slice(&2u16.to_be_bytes());
+    tiff.extend_from_slice(&0x0131u16.to_be_bytes());
+    tiff.extend_from_slice(&2u16.to_be_bytes());
+    tiff.extend_from_slice(&(SOFTWARE.len() as u32 + 1).to_be_bytes());
+    tiff.extend_from_slice(&software_offset.to_be_bytes());
+    tiff.extend_from_slice(&0x8769u16.to_be_bytes());
+    tiff.extend_from_slice(&4u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&exif_ifd_offset.to_be_bytes());
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    tiff.extend_from_slice(&software);
+
+    // Exif IFD: UserComment (UNDEFINED)
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&0x9286u16.to_be_bytes());
+    tiff.extend_from_slice(&7u16.to_be_bytes());
+    tiff.extend_from_slice(&(comment.len() as u32).to_be_bytes());
+    tiff.extend_from_slice(&comment_offset.to_be_bytes());
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    tiff.extend_from_slice(&comment);
+    tiff
+}
+
+/// Escape text for an XML attribute
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\n' => escaped.push_str("&#xA;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Build an XMP packet with the parameters and the prompt as the description
+fn xmp_packet(parameters: &str) -> Vec<u8> {
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+            "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+            "<rdf:Description rdf:about=\"\" xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" xmlns:txt2img=\"{}\" ",
+            "xmp:CreatorTool=\"{}\" txt2img:parameters=\"{}\"/>",
+            "</rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>"
+        ),
+        XMP_NAMESPACE,
+        SOFTWARE,
+        escape_xml(parameters)
+    )
+    .into_bytes()
+}
+
+/// Build a JPEG APP1 segment
+fn jpeg_app1(
+    header: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    // Segment length includes the 2 length bytes
+    let length = header.len() + payload.len() + 2;
+    if length > u16::MAX as usize {
+        bail!("Metadata too large for JPEG (metadata.rs)");
+    }
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&(length as u16).to_be_bytes());
+    segment.extend_from_slice(header);
+    segment.extend_from_slice(payload);
+    Ok(segment)
+}
+
+/// Insert EXIF and XMP APP1 segments after SOI (and JFIF APP0 if present)
+fn embed_jpeg(
+    bytes: Vec<u8>,
+    parameters: &str,
+) -> Result<Vec<u8>> {
+    if bytes.len() < 4 || bytes[..2] != [0xFF, 0xD8] {
+        bail!("Invalid JPEG (metadata.rs)");
+    }
+
+    let mut offset = 2;
+    if bytes[2..4] == [0xFF, 0xE0] && bytes.len() >= 6 {
+        offset += 2 + u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+        if offset > bytes.len() {
+            bail!(CodedError::new(
+                ErrorCode::Provider,
+                "Truncated JPEG APP0 segment (metadata.rs)"
+            ));
+        }
+    }
+
+    let mut out = Vec::with_capacity(bytes.len() + parameters.len() * 4 + 512);
+    out.extend_from_slice(&bytes[..offset]);
+    out.extend(jpeg_app1(EXIF_HEADER, &exif_payload(parameters))?);
+    out.extend(jpeg_app1(XMP_HEADER, &xmp_packet(parameters))?);
+    out.extend_from_slice(&bytes[offset..]);
+    Ok(out)
+}
+
+/// Build a RIFF chunk padded to an even length
+fn riff_chunk(
+    kind: &[u8; 4],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 9);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+/// Get the canvas size and alpha flag from a simple (VP8 or VP8L) WebP bitstream
+fn webp_canvas(
+    kind: &[u8],
+    data: &[u8],
+) -> Result<(u32, u32, bool)> {
+    match kind {
+        b"VP8L" if data.len() >= 5 => {
+            let bits = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+            let width = (bits & 0x3FFF) + 1;
+            let height = ((bits >> 14) & 0x3FFF) + 1;
+            let alpha = (bits >> 28) & 1 == 1;
+            Ok((width, height, alpha))
+        }
+        b"VP8 " if data.len() >= 10 => {
+            let width = u16::from_le_bytes([data[6], data[7]]) as u32 & 0x3FFF;
+            let height = u16::from_le_bytes([data[8], data[9]]) as u32 & 0x3FFF;
+            Ok((width, height, false))
+        }
+        _ => bail!("Unsupported WebP bitstream (metadata.rs)"),
+    }
+}
+
+/// Convert to the extended WebP format and append EXIF and XMP chunks
+fn embed_webp(
+    bytes: Vec<u8>,
+    parameters: &str,
+) -> Result<Vec<u8>> {
+    if bytes.len() < 20 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        bail!("Invalid WebP (metadata.rs)");
+    }
+
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+    const ALPHA_FLAG: u8 = 0x10;
+
+    let kind = &bytes[12..16];
+    let mut body = Vec::with_capacity(bytes.len() + parameters.len() * 4 + 512);
+    if kind == b"VP8X" {
+        // The VP8X chunk is an 8-byte header and 10 bytes of flags and canvas size
+        if bytes.len() < 30 {
+            bail!(CodedError::new(
+                ErrorCode::Provider,
+                "Truncated WebP VP8X chunk (metadata.rs)"
+            ));
+        }
+        // Already extended, so keep every chunk except the old metadata that would be duplicated
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let chunk_kind = &bytes[offset..offset + 4];
+            let length = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into()?) as usize;
+            let chunk = bytes
+                .get(offset..offset + 8 + length)
+                .ok_or_else(|| CodedError::new(ErrorCode::Provider, "Truncated WebP chunk (metadata.rs)"))?;
+            offset += 8 + length + length % 2;
+            if chunk_kind == b"EXIF" || chunk_kind == b"XMP " {
+                continue;
+            }
+            body.extend_from_slice(chunk);
+            if length % 2 == 1 {
+                body.push(0);
+            }
+        }
+        // Then set the flags on the existing header
+        body[8] |= EXIF_FLAG | XMP_FLAG;
+    } else {
+        let (width, height, alpha) = webp_canvas(kind, &bytes[20..])?;
+        // The canvas size is stored minus one, so an empty image can't be represented
+        if width == 0 || height == 0 {
+            bail!(CodedError::new(
+                ErrorCode::Provider,
+                format!("Invalid WebP with a {width}x{height} canvas (metadata.rs)")
+            ));
+        }
+        let mut vp8x = vec![EXIF_FLAG | XMP_FLAG | if alpha { ALPHA_FLAG } else { 0 }, 0, 0, 0];
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        body.extend(riff_chunk(b"VP8X", &vp8x));
+        body.extend_from_slice(&bytes[12..]);
+    }
+    body.extend(riff_chunk(b"EXIF", &exif_payload(parameters)));
+    body.extend(riff_chunk(b"XMP ", &xmp_packet(parameters)));
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend(body);
+    Ok(out)
+}
+
+/// Read the `parameters` text from PNG, JPEG, or WebP bytes
+pub fn read_parameters(bytes: &[u8]) -> Result<Option<String>> {
+    let format = guess_format(bytes).context("Unknown image format (metadata.rs)")?;
+    match format {
+        ImageFormat::Png => read_png(bytes),
+        ImageFormat::Jpeg => read_jpeg(bytes),
+        ImageFormat::WebP => read_webp(bytes),
+        _ => bail!("Metadata not supported for {format:?} (metadata.rs)"),
+    }
+}
+
+/// Find the `parameters` tEXt or uncompressed iTXt chunk
+fn read_png(bytes: &[u8]) -> Result<Option<String>> {
+    // Skip the 8-byte signature
+    let mut offset = 8;
+    while offset + 12 <= bytes.len() {
+        let length = u32::from_be_bytes(bytes[offset..offset + 4].try_into()?) as usize;
+        let kind = &bytes[offset + 4..offset + 8];
+        let data = bytes
+            .get(offset + 8..offset + 8 + length)
+            .context("Truncated PNG chunk (metadata.rs)")?;
+        offset += length + 12;
+
+        let Some(separator) = data.iter().position(|b| *b == 0) else {
+            continue;
+        };
+        if &data[..separator] != PARAMETERS_KEY.as_bytes() {
+            continue;
+        }
+
+        let rest = &data[separator + 1..];
+        match kind {
+            // Latin-1 maps directly to the first 256 code points
+            b"tEXt" => return Ok(Some(rest.iter().map(|b| *b as char).collect())),
+            // Compression flag and method, then null-terminated language tag and translated keyword
+            b"iTXt" if rest.len() >= 2 && rest[0] == 0 => {
+                let text = rest[2..].splitn(3, |b| *b == 0).nth(2).unwrap_or_default();
+                return Ok(Some(String::from_utf8_lossy(text).into_owned()));
+            }
+            _ => continue,
+        }
+    }
+    Ok(None)
+}
+
+/// Read a TIFF value as u16 or u32 in the given byte order
+fn tiff_u16(
+    tiff: &[u8],
+    offset: usize,
+    big_endian: bool,
+) -> Option<u16> {
+    let bytes: [u8; 2] = tiff.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    })
+}
+
+fn tiff_u32(
+    tiff: &[u8],
+    offset: usize,
+    big_endian: bool,
+) -> Option<u32> {
+    let bytes: [u8; 4] = tiff.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    })
+}
+
+/// Find a tag's value offset and count in the IFD at `ifd`
+fn tiff_find_tag(
+    tiff: &[u8],
+    ifd: usize,
+    tag: u16,
+    big_endian: bool,
+) -> Option<(usize, usize)> {
+    let entries = tiff_u16(tiff, ifd, big_endian)? as usize;
+    (0..entries).find_map(|i| {
+        let entry = ifd + 2 + i * 12;
+        if tiff_u16(tiff, entry, big_endian)? != tag {
+            return None;
+        }
+        let count = tiff_u32(tiff, entry + 4, big_endian)? as usize;
+        // Values of 4 bytes or less are stored inline
+        let offset = if count <= 4 {
+            entry + 8
+        } else {
+            tiff_u32(tiff, entry + 8, big_endian)? as usize
+        };
+        Some((offset, count))
+    })
+}
+
+/// Read the Exif UserComment from a TIFF structure
+fn read_exif_user_comment(tiff: &[u8]) -> Option<String> {
+    let tiff = tiff.strip_prefix(EXIF_HEADER).unwrap_or(tiff);
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+
+    let ifd0 = tiff_u32(tiff, 4, big_endian)? as usize;
+    let (exif_ifd, _) = tiff_find_tag(tiff, ifd0, 0x8769, big_endian)?;
+    let exif_ifd = tiff_u32(tiff, exif_ifd, big_endian)? as usize;
+    let (offset, count) = tiff_find_tag(tiff, exif_ifd, 0x9286, big_endian)?;
+    let comment = tiff.get(offset..offset + count)?;
+
+    let (code, text) = comment.split_at(8.min(comment.len()));
+    let text = match code {
+        b"UNICODE\0" => {
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|c| {
+                    if big_endian {
+                        u16::from_be_bytes([c[0], c[1]])
+                    } else {
+                        u16::from_le_bytes([c[0], c[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+    Some(text.trim_end_matches('\0').to_string())
+}
+
+/// Read the `txt2img:parameters` attribute from an XMP packet
+fn read_xmp_parameters(xmp: &[u8]) -> Option<String> {
+    let xmp = String::from_utf8_lossy(xmp);
+    let start = xmp.find("txt2img:parameters=\"")? + "txt2img:parameters=\"".len();
+    let end = start + xmp[start..].find('"')?;
+    let text = xmp[start..end]
+        .replace("&#xA;", "\n")
+        .replace("&quot;", "\"")
+        .replace("&gt;", ">")
+        .replace("&lt;", "<")
+        .replace("&amp;", "&");
+    Some(text)
+}
+
+/// Find the EXIF or XMP APP1 segment before the image data
+fn read_jpeg(bytes: &[u8]) -> Result<Option<String>> {
+    let mut offset = 2;
+    let mut xmp = None;
+    while offset + 4 <= bytes.len() && bytes[offset] == 0xFF {
+        let marker = bytes[offset + 1];
+        // Start of scan, so no more metadata segments
+        if marker == 0xDA {
+            break;
+        }
+        let length = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+        let data = bytes
+            .get(offset + 4..offset + 2 + length)
+            .context("Truncated JPEG segment (metadata.rs)")?;
+        offset += 2 + length;
+
+        if marker != 0xE1 {
+            continue;
+        }
+        if data.starts_with(EXIF_HEADER) {
+            if let Some(text) = read_exif_user_comment(data) {
+                return Ok(Some(text));
+            }
+        } else if let Some(packet) = data.strip_prefix(XMP_HEADER) {
+            xmp = read_xmp_parameters(packet);
+        }
+    }
+    Ok(xmp)
+}
+
+/// Find the EXIF or XMP chunk in a RIFF container
+fn read_webp(bytes: &[u8]) -> Result<Option<String>> {
+    let mut offset = 12;
+    let mut xmp = None;
+    while offset + 8 <= bytes.len() {
+        let kind = &bytes[offset..offset + 4];
+        let length = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into()?) as usize;
+        let data = bytes
+            .get(offset + 8..offset + 8 + length)
+            .context("Truncated WebP chunk (metadata.rs)")?;
+        // Chunks are padded to an even length
+        offset += 8 + length + length % 2;
+
+        match kind {
+            b"EXIF" => {
+                if let Some(text) = read_exif_user_comment(data) {
+                    return Ok(Some(text));
+                }
+            }
+            b"XMP " => xmp = read_xmp_parameters(data),
+            _ => {}
+        }
+    }
+    Ok(xmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, RgbaImage};
+
+    use super::*;
+    use crate::error::error_code;
+
+    const PARAMETERS: &str = "a cat, in a hat\nNegative prompt: blurry, dark\nSteps: 4, Seed: 42, Size: 64x32";
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, [255, 0, 0, 255].into()));
+        let image = if format == ImageFormat::Jpeg {
+            DynamicImage::ImageRgb8(image.to_rgb8())
+        } else {
+            image
+        };
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), format).unwrap();
+        bytes
+    }
+
+    fn round_trip(format: ImageFormat) {
+        let metadata = ImageMetadata::from_parameters(PARAMETERS);
+        let bytes = embed_metadata(encode(format), format, &metadata).unwrap();
+        image::load_from_memory_with_format(&bytes, format).unwrap();
+        assert_eq!(read_parameters(&bytes).unwrap().as_deref(), Some(PARAMETERS));
+    }
+
+    #[test]
+    fn round_trips_png() {
+        round_trip(ImageFormat::Png);
+    }
+
+    #[test]
+    fn round_trips_jpeg() {
+        round_trip(ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn round_trips_webp() {
+        round_trip(ImageFormat::WebP);
+    }
+
+    #[test]
+    fn round_trips_utf8_png() {
+        let bytes = embed_png(encode(ImageFormat::Png), "猫 🐱").unwrap();
+        assert_eq!(read_parameters(&bytes).unwrap().as_deref(), Some("猫 🐱"));
+    }
+
+    #[test]
+    fn replaces_existing_webp_metadata() {
+        let first = embed_webp(encode(ImageFormat::WebP), "first").unwrap();
+        let second = embed_webp(first, "second").unwrap();
+        assert_eq!(read_parameters(&second).unwrap().as_deref(), Some("second"));
+
+        let count = |kind: &[u8]| second.windows(4).filter(|w| *w == kind).count();
+        assert_eq!(count(b"EXIF"), 1);
+        assert_eq!(count(b"XMP "), 1);
+        assert_eq!(count(b"VP8X"), 1);
+    }
+
+    #[test]
+    fn reads_nothing_without_parameters() {
+        assert_eq!(read_parameters(&encode(ImageFormat::Png)).unwrap(), None);
+        assert_eq!(read_parameters(&encode(ImageFormat::Jpeg)).unwrap(), None);
+        assert_eq!(read_parameters(&encode(ImageFormat::WebP)).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_truncated_jpeg_app0() {
+        let bytes = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x40, 0x4A, 0x46];
+        let e = embed_jpeg(bytes, PARAMETERS).unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::Provider);
+    }
+
+    #[test]
+    fn rejects_truncated_webp_vp8x() {
+        let mut bytes = b"RIFF\x0c\0\0\0WEBPVP8X\x0a\0\0\0".to_vec();
+        assert_eq!(bytes.len(), 20);
+        let e = embed_webp(bytes.clone(), PARAMETERS).unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::Provider);
+
+        bytes.push(0);
+        assert!(embed_webp(bytes, PARAMETERS).is_err());
+    }
+
+    #[test]
+    fn rejects_zero_sized_webp() {
+        let mut bytes = b"RIFF\0\0\0\0WEBPVP8 \x0a\0\0\0".to_vec();
+        bytes.extend_from_slice(&[0, 0, 0, 0x9D, 0x01, 0x2A, 0, 0, 8, 0]);
+        let e = embed_webp(bytes, PARAMETERS).unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::Provider);
+    }
+
+    #[test]
+    fn rejects_invalid_images() {
+        assert!(embed_png(b"not a png".to_vec(), PARAMETERS).is_err());
+        assert!(embed_jpeg(b"not a jpeg".to_vec(), PARAMETERS).is_err());
+        assert!(embed_webp(b"not a webp".to_vec(), PARAMETERS).is_err());
+        assert!(read_parameters(b"not an image").is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_chunks() {
+        // Cut the parameters chunk short
+        let png = embed_png(encode(ImageFormat::Png), PARAMETERS).unwrap();
+        let start = png.windows(4).position(|w| w == b"tEXt").unwrap();
+        assert!(read_png(&png[..start + 20]).is_err());
+
+        let webp = embed_webp(encode(ImageFormat::WebP), PARAMETERS).unwrap();
+        let start = webp.windows(4).position(|w| w == b"EXIF").unwrap();
+        assert!(read_webp(&webp[..start + 20]).is_err());
+    }
+
+    #[test]
+    fn parses_fields_with_commas() {
+        let fields = parse_fields("Steps: 4, Style: vivid, bright, Clip skip: 2");
+        assert_eq!(
+            fields,
+            vec![
+                ("Steps".to_string(), "4".to_string()),
+                ("Style".to_string(), "vivid, bright".to_string()),
+                ("Clip skip".to_string(), "2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_no_fields_from_prompt() {
+        assert!(parse_fields("a cat, in a hat").is_empty());
+    }
+
+    #[test]
+    fn parses_parameters() {
+        let metadata = ImageMetadata::from_parameters(
+            "a cat, in a hat\nNegative prompt: blurry,\ndark\nSteps: 4, CFG scale: 7.5, Seed: 42, Size: 64x32, \
+             Model: m, Service: hf, Clip skip: 2",
+        );
+        assert_eq!(metadata.prompt, "a cat, in a hat");
+        assert_eq!(metadata.negative_prompt.as_deref(), Some("blurry,\ndark"));
+        assert_eq!(metadata.steps, Some(4));
+        assert_eq!(metadata.cfg, Some(7.5));
+        assert_eq!(metadata.seed, Some(42));
+        assert_eq!((metadata.width, metadata.height), (Some(64), Some(32)));
+        assert_eq!(metadata.model.as_deref(), Some("m"));
+        assert_eq!(metadata.service, Some(ServiceId::from("hf")));
+        assert_eq!(metadata.extra.get("Clip skip").map(String::as_str), Some("2"));
+    }
+
+    #[test]
+    fn parses_prompt_without_fields() {
+        let metadata = ImageMetadata::from_parameters("a cat, in a hat\non two lines");
+        assert_eq!(metadata.prompt, "a cat, in a hat\non two lines");
+        assert_eq!(
+            metadata,
+            ImageMetadata {
+                prompt: metadata.prompt.clone(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn round_trips_parameters() {
+        let metadata = ImageMetadata {
+            prompt: "a cat, in a hat".to_string(),
+            negative_prompt: Some("blurry".to_string()),
+            steps: Some(4),
+            cfg: Some(7.5),
+            seed: Some(42),
+            width: Some(64),
+            height: Some(32),
+            strength: Some(0.5),
+            model: Some("m".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ImageMetadata::from_parameters(&metadata.to_parameters()), metadata);
+    }
+
+    #[test]
+    fn requires_service_and_model_to_rerun() {
+        let metadata = ImageMetadata {
+            prompt: "a cat".to_string(),
+            ..Default::default()
+        };
+        let error = metadata.to_request(None, None).unwrap_err();
+        assert_eq!(error_code(&error), ErrorCode::InvalidRequest);
+        assert!(error.to_string().contains("--service"), "{error}");
+
+        let service = ServiceId::from("hf");
+        let error = metadata.to_request(Some(&service), None).unwrap_err();
+        assert!(error.to_string().contains("--model"), "{error}");
+
+        let model = ModelId::from("sd35-large-turbo");
+        let request = metadata.to_request(Some(&service), Some(&model)).unwrap();
+        assert_eq!(request.service, service);
+        assert_eq!(request.model.id, model);
+
+        // Recorded values win over the fallbacks
+        let metadata = ImageMetadata {
+            service: Some(service.clone()),
+            model: Some("stabilityai/stable-diffusion-3.5-large-turbo".to_string()),
+            ..metadata
+        };
+        let request = metadata
+            .to_request(Some(&ServiceId::from("openai")), Some(&ModelId::from("dalle3")))
+            .unwrap();
+        assert_eq!(request.service, service);
+        assert_eq!(request.model.id, model);
+    }
+}
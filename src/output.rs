@@ -0,0 +1,165 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::capabilities::Capabilities;
+use crate::client::supported_parameters;
+use crate::error::{error_code, ErrorCode};
+use crate::result::GenerationResult;
+use crate::services::{Model, ModelId, OpenAIImageStyle, Service, ServiceId, ServiceKind};
+
+/// Format for results printed to stdout
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Image file format
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFileFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl From<ImageFileFormat> for ImageFormat {
+    fn from(format: ImageFileFormat) -> Self {
+        match format {
+            ImageFileFormat::Png => ImageFormat::Png,
+            ImageFileFormat::Jpeg => ImageFormat::Jpeg,
+            ImageFileFormat::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// A saved image
+#[derive(Debug, Serialize)]
+pub struct ImageOutput<'a> {
+    pub path: &'a str,
+    pub service: &'a ServiceId,
+    pub model: &'a ModelId,
+    pub model_name: &'a str,
+    pub seed: Option<u64>,
+    pub mime_type: &'a str,
+    /// Seconds taken by the provider request
+    pub seconds: f64,
+}
+
+/// Images saved by a generation
+#[derive(Debug, Serialize)]
+pub struct GenerationOutput<'a> {
+    pub images: Vec<ImageOutput<'a>>,
+    /// Seconds since the command started
+    pub seconds: f64,
+}
+
+impl<'a> GenerationOutput<'a> {
+    pub fn new(
+        file_paths: &'a [String],
+        results: &'a [GenerationResult],
+        seconds: f64,
+    ) -> Self {
+        let images = file_paths
+            .iter()
+            .zip(results)
+            .map(|(path, result)| ImageOutput {
+                path,
+                service: &result.request.service,
+                model: &result.request.model.id,
+                model_name: &result.request.model.name,
+                seed: result.seed,
+                mime_type: &result.mime_type,
+                seconds: result.elapsed.as_secs_f64(),
+            })
+            .collect();
+        Self { images, seconds }
+    }
+}
+
+/// A model with its defaults
+#[derive(Debug, Serialize)]
+pub struct ModelOutput<'a> {
+    pub id: &'a ModelId,
+    pub name: &'a str,
+    pub default: bool,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub steps: Option<u8>,
+    pub cfg: Option<f32>,
+    pub style: Option<&'a OpenAIImageStyle>,
+    pub negative_prompt: Option<&'a str>,
+    pub sampler: Option<&'a str>,
+    /// Parameters sent to the provider for this model
+    pub supported_parameters: Vec<&'static str>,
+    pub capabilities: &'a Capabilities,
+}
+
+impl<'a> ModelOutput<'a> {
+    /// Uses the same supported parameters as the ignored parameter warnings
+    pub fn new(
+        model: &'a Model,
+        service: &'a Service,
+    ) -> Self {
+        let mut parameters = vec!["prompt"];
+        parameters.extend(supported_parameters(service.kind, model));
+
+        Self {
+            id: &model.id,
+            name: &model.name,
+            default: model.id == service.default.id,
+            width: model.width,
+            height: model.height,
+            steps: model.steps,
+            cfg: model.cfg,
+            style: model.style.as_ref(),
+            negative_prompt: model.negative_prompt.as_deref(),
+            sampler: model.sampler.as_deref(),
+            supported_parameters: parameters,
+            capabilities: &model.capabilities,
+        }
+    }
+}
+
+/// A service with its default model
+#[derive(Debug, Serialize)]
+pub struct ServiceOutput<'a> {
+    pub id: &'a ServiceId,
+    #[serde(rename = "type")]
+    pub kind: ServiceKind,
+    pub default: bool,
+    pub default_model: &'a ModelId,
+}
+
+/// Error body with a stable code
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Top-level error output
+#[derive(Debug, Serialize)]
+pub struct ErrorOutput {
+    pub error: ErrorBody,
+}
+
+impl From<&anyhow::Error> for ErrorOutput {
+    fn from(error: &anyhow::Error) -> Self {
+        Self {
+            error: ErrorBody {
+                code: error_code(error),
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+/// Print a value as pretty JSON to stdout
+pub fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
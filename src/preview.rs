@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufWriter, Cursor, IsTerminal, Write};
+
+use anyhow::{Context, Result};
+use base64::prelude::*;
+use console::Term;
+use image::imageops::FilterType;
+use image::{load_from_memory, DynamicImage, ImageFormat, RgbImage};
+use log::debug;
+use strum::Display;
+
+/// Kitty payloads must be sent in chunks of at most 4096 bytes
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// Terminal cells are roughly twice as tall as they are wide
+const CELL_ASPECT: u32 = 2;
+
+/// Pixel width assumed for a cell when the terminal needs pixels (sixel)
+const CELL_WIDTH: u32 = 10;
+
+/// Terminal graphics protocol used for the preview
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Protocol {
+    Kitty,
+    Iterm,
+    Sixel,
+    HalfBlocks,
+}
+
+impl Protocol {
+    /// Detect the protocol from the environment, falling back to half-blocks
+    /// `TERM` and `LC_TERMINAL` are used because they are forwarded over SSH
+    pub fn detect() -> Self {
+        let var = |name| env::var(name).unwrap_or_default();
+        let term = var("TERM");
+        let term_program = var("TERM_PROGRAM");
+
+        if env::var_os("KITTY_WINDOW_ID").is_some() || term == "xterm-kitty" || term == "xterm-ghostty" {
+            Protocol::Kitty
+        } else if term_program == "iTerm.app" || term_program == "WezTerm" || var("LC_TERMINAL") == "iTerm2" {
+            Protocol::Iterm
+        } else if term.contains("sixel") || term == "foot" || term.starts_with("mlterm") || term == "yaft-256color" {
+            Protocol::Sixel
+        } else {
+            Protocol::HalfBlocks
+        }
+    }
+}
+
+/// Display an image inline on stderr, scaled to fit the terminal
+pub fn preview_image(image_bytes: &[u8]) -> Result<()> {
+    let stderr = io::stderr().lock();
+    if !stderr.is_terminal() {
+        debug!("Skipping preview because stderr isn't a terminal");
+        return Ok(());
+    }
+
+    let image = load_from_memory(image_bytes).context("Failed to decode image for preview (preview.rs)")?;
+    let (columns, rows) = fit_to_terminal(&image);
+    let protocol = Protocol::detect();
+    debug!("Previewing with {protocol} in {columns}x{rows} cells");
+
+    // Buffered since sixel and half-blocks are written a few bytes at a time
+    let mut output = BufWriter::new(stderr);
+    match protocol {
+        Protocol::Kitty => kitty(&mut output, &image, columns)?,
+        Protocol::Iterm => iterm(&mut output, &image, columns)?,
+        Protocol::Sixel => {
+            let width = (columns * CELL_WIDTH).min(image.width());
+            sixel(
+                &mut output,
+                &image.resize(width, u32::MAX, FilterType::Triangle).to_rgb8(),
+            )?
+        }
+        Protocol::HalfBlocks => half_blocks(
+            &mut output,
+            &image.resize_exact(columns, rows * 2, FilterType::Triangle).to_rgb8(),
+        )?,
+    }
+
+    writeln!(output)?;
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Get the size in cells that fits the terminal width without overflowing its height
+fn fit_to_terminal(image: &DynamicImage) -> (u32, u32) {
+    let (term_rows, term_columns) = Term::stderr().size();
+    let max_columns = u32::from(term_columns).max(1);
+    // Leave a row for the prompt below the image
+    let max_rows = u32::from(term_rows).saturating_sub(1).max(1);
+
+    let (width, height) = (image.width().max(1), image.height().max(1));
+    let columns = max_columns
+        .min(max_rows * CELL_ASPECT * width / height)
+        .min(width)
+        .max(1);
+    let rows = (columns * height / width / CELL_ASPECT).max(1);
+
+    (columns, rows)
+}
+
+/// Encode the image as PNG for protocols that transfer files
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>> {
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .context("Failed to encode image for preview (preview.rs)")?;
+    Ok(png)
+}
+
+/// Kitty graphics protocol (https://sw.kovidgoyal.net/kitty/graphics-protocol/)
+fn kitty(
+    output: &mut impl Write,
+    image: &DynamicImage,
+    columns: u32,
+) -> Result<()> {
+    let payload = BASE64_STANDARD.encode(encode_png(image)?);
+    let chunks: Vec<_> = payload.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        // Base64 is ASCII so every chunk is valid UTF-8
+        let chunk = std::str::from_utf8(chunk)?;
+        if i == 0 {
+            write!(output, "\x1b_Gf=100,a=T,c={columns},m={more};{chunk}\x1b\\")?;
+        } else {
+            write!(output, "\x1b_Gm={more};{chunk}\x1b\\")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// iTerm2 inline images (https://iterm2.com/documentation-images.html)
+fn iterm(
+    output: &mut impl Write,
+    image: &DynamicImage,
+    columns: u32,
+) -> Result<()> {
+    let png = encode_png(image)?;
+    write!(
+        output,
+        "\x1b]1337;File=inline=1;size={};width={columns};preserveAspectRatio=1:{}\x07",
+        png.len(),
+        BASE64_STANDARD.encode(&png)
+    )?;
+    Ok(())
+}
+
+/// Sixel graphics quantized to a 6x6x6 color cube
+fn sixel(
+    output: &mut impl Write,
+    image: &RgbImage,
+) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    let index = |x, y| {
+        let [r, g, b] = image.get_pixel(x, y).0;
+        let level = |c: u8| (u16::from(c) * 5 + 127) / 255;
+        level(r) * 36 + level(g) * 6 + level(b)
+    };
+
+    write!(output, "\x1bPq\"1;1;{width};{height}")?;
+    for i in 0..216u16 {
+        // Sixel colors are percentages
+        let percent = |level: u16| level * 20;
+        write!(
+            output,
+            "#{i};2;{};{};{}",
+            percent(i / 36),
+            percent(i / 6 % 6),
+            percent(i % 6)
+        )?;
+    }
+
+    // Each band is six rows of pixels, drawn once per color
+    for band in (0..height).step_by(6) {
+        let mut masks: HashMap<u16, Vec<u8>> = HashMap::new();
+        for dy in 0..6.min(height - band) {
+            for x in 0..width {
+                let mask = masks
+                    .entry(index(x, band + dy))
+                    .or_insert_with(|| vec![0; width as usize]);
+                mask[x as usize] |= 1 << dy;
+            }
+        }
+
+        let mut colors: Vec<_> = masks.into_iter().collect();
+        colors.sort_unstable_by_key(|(color, _)| *color);
+        for (color, mask) in colors {
+            write!(output, "#{color}")?;
+            write_run_length(output, &mask)?;
+            output.write_all(b"$")?;
+        }
+        output.write_all(b"-")?;
+    }
+
+    output.write_all(b"\x1b\\")
+}
+
+/// Write sixel characters with repeats compressed as `!<count><char>`
+fn write_run_length(
+    output: &mut impl Write,
+    mask: &[u8],
+) -> io::Result<()> {
+    let mut i = 0;
+    while i < mask.len() {
+        let run = mask[i..].iter().take_while(|&&m| m == mask[i]).count();
+        let c = char::from(b'?' + mask[i]);
+        if run > 3 {
+            write!(output, "!{run}{c}")?;
+        } else {
+            for _ in 0..run {
+                write!(output, "{c}")?;
+            }
+        }
+        i += run;
+    }
+    Ok(())
+}
+
+/// Upper half blocks with the top pixel as foreground and the bottom pixel as background
+fn half_blocks(
+    output: &mut impl Write,
+    image: &RgbImage,
+) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    for y in (0..height).step_by(2) {
+        if y > 0 {
+            writeln!(output)?;
+        }
+        for x in 0..width {
+            let [r, g, b] = image.get_pixel(x, y).0;
+            write!(output, "\x1b[38;2;{r};{g};{b}m")?;
+            if y + 1 < height {
+                let [r, g, b] = image.get_pixel(x, y + 1).0;
+                write!(output, "\x1b[48;2;{r};{g};{b}m")?;
+            }
+            write!(output, "▀")?;
+        }
+        write!(output, "\x1b[0m")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    /// Writer that fails like a closed pipe
+    struct BrokenPipe;
+
+    impl Write for BrokenPipe {
+        fn write(
+            &mut self,
+            _buf: &[u8],
+        ) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_half_blocks() {
+        let image = RgbImage::from_fn(1, 3, |_, y| Rgb([y as u8, 0, 0]));
+        let mut output = Vec::new();
+        half_blocks(&mut output, &image).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\x1b[38;2;0;0;0m\x1b[48;2;1;0;0m▀\x1b[0m\n\x1b[38;2;2;0;0m▀\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn compresses_sixel_runs() {
+        let mut output = Vec::new();
+        write_run_length(&mut output, &[1, 1, 1, 1, 2, 2]).unwrap();
+        assert_eq!(output, b"!4@AA");
+    }
+
+    #[test]
+    fn reports_write_errors() {
+        let image = RgbImage::new(2, 2);
+        assert_eq!(
+            sixel(&mut BrokenPipe, &image).unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(
+            half_blocks(&mut BrokenPipe, &image).unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
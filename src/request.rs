@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use image::guess_format;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::capabilities::{AspectRatio, Size};
+use crate::error::{CodedError, ErrorCode};
+use crate::mask::Mask;
+use crate::output::ImageFileFormat;
+use crate::services::{
+    get_or_init_services, Model, ModelId, OpenAIImageBackground, OpenAIImageQuality, OpenAIImageStyle,
+    OpenAIModeration, ServiceId,
+};
+
+/// A text-to-image request resolved against a service and model
+/// Parameters left as `None` fall back to the model defaults
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GenerationRequest {
+    pub service: ServiceId,
+    pub model: Model,
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    /// Option the width and height came from, to name it in messages
+    #[serde(skip)]
+    pub size_source: SizeSource,
+    pub steps: Option<u8>,
+    pub cfg: Option<f32>,
+    pub seed: Option<u64>,
+    pub style: Option<OpenAIImageStyle>,
+    #[serde(default)]
+    pub sampler: Option<String>,
+    /// Image to start from instead of noise (image-to-image)
+    #[serde(default)]
+    pub init_image: Option<PathBuf>,
+    /// How much to change the init image, from 0 (keep) to 1 (replace)
+    #[serde(default)]
+    pub strength: Option<f32>,
+    /// PNG whose transparent areas are inpainted in the init image
+    #[serde(default)]
+    pub mask: Option<PathBuf>,
+    /// Image quality (OpenAI)
+    #[serde(default)]
+    pub quality: Option<OpenAIImageQuality>,
+    /// Image background (GPT Image)
+    #[serde(default)]
+    pub background: Option<OpenAIImageBackground>,
+    /// Format the provider should return (GPT Image)
+    #[serde(default)]
+    pub output_format: Option<ImageFileFormat>,
+    /// Compression level from 0 to 100 for JPEG and WebP (GPT Image)
+    #[serde(default)]
+    pub compression: Option<u8>,
+    /// Moderation level (GPT Image)
+    #[serde(default)]
+    pub moderation: Option<OpenAIModeration>,
+    /// Extra provider options merged over the model options
+    #[serde(default)]
+    pub options: HashMap<String, Value>,
+}
+
+/// Option that set the width and height of a request
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SizeSource {
+    /// Width and height given as is
+    #[default]
+    Exact,
+    /// Size snapped to the model
+    Size,
+    /// Aspect ratio resolved by the model
+    Aspect,
+}
+
+impl GenerationRequest {
+    /// Create a builder for a prompt
+    pub fn builder(prompt: impl Into<String>) -> GenerationRequestBuilder {
+        GenerationRequestBuilder {
+            prompt: prompt.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Get the negative prompt or None
+    pub fn get_negative_prompt(&self) -> Option<&str> {
+        self.negative_prompt
+            .as_deref()
+            .or(self.model.negative_prompt.as_deref())
+    }
+
+    /// Get the number of steps or None if the model doesn't support it
+    pub fn get_steps(&self) -> Option<u8> {
+        self.steps.or(self.model.steps)
+    }
+
+    /// Get the guidance scale or None if the model doesn't support it
+    pub fn get_cfg(&self) -> Option<f32> {
+        self.cfg.or(self.model.cfg)
+    }
+
+    /// Get the width or None if the model doesn't support it
+    pub fn get_width(&self) -> Option<u16> {
+        self.width.or(self.model.width)
+    }
+
+    /// Get the height or None if the model doesn't support it
+    pub fn get_height(&self) -> Option<u16> {
+        self.height.or(self.model.height)
+    }
+
+    /// Get the style or None if the model doesn't support it
+    pub fn get_style(&self) -> Option<&OpenAIImageStyle> {
+        self.style.as_ref().or(self.model.style.as_ref())
+    }
+
+    /// Get the sampler or None if the model doesn't support it
+    pub fn get_sampler(&self) -> Option<&str> {
+        self.sampler.as_deref().or(self.model.sampler.as_deref())
+    }
+
+    /// Read the init image or None for text-to-image
+    pub fn read_init_image(&self) -> Result<Option<Vec<u8>>> {
+        let Some(path) = &self.init_image else {
+            return Ok(None);
+        };
+        let display = path.display();
+        let bytes = fs::read(path).context(format!("Failed to read init image `{display}` (request.rs)"))?;
+        if guess_format(&bytes).is_err() {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!("Init image `{display}` is not a supported image (request.rs)")
+            ));
+        }
+        Ok(Some(bytes))
+    }
+
+    /// Read the mask resized to the init image or None to change the whole image
+    pub fn read_mask(
+        &self,
+        init_image: &[u8],
+    ) -> Result<Option<Mask>> {
+        self.mask
+            .as_deref()
+            .map(|path| Mask::read(path, init_image))
+            .transpose()
+    }
+
+    /// Get the model options with the request options merged over them
+    pub fn get_options(&self) -> HashMap<String, Value> {
+        let mut options = self.model.options.clone().unwrap_or_default();
+        options.extend(self.options.clone());
+        options
+    }
+}
+
+/// Builder for a generation request
+#[derive(Clone, Debug, Default)]
+pub struct GenerationRequestBuilder {
+    service: Option<ServiceId>,
+    model: Option<ModelId>,
+    prompt: String,
+    negative_prompt: Option<String>,
+    width: Option<u16>,
+    height: Option<u16>,
+    size: Option<Size>,
+    aspect: Option<AspectRatio>,
+    steps: Option<u8>,
+    cfg: Option<f32>,
+    seed: Option<u64>,
+    style: Option<OpenAIImageStyle>,
+    sampler: Option<String>,
+    init_image: Option<PathBuf>,
+    strength: Option<f32>,
+    mask: Option<PathBuf>,
+    quality: Option<OpenAIImageQuality>,
+    background: Option<OpenAIImageBackground>,
+    output_format: Option<ImageFileFormat>,
+    compression: Option<u8>,
+    moderation: Option<OpenAIModeration>,
+    options: HashMap<String, Value>,
+}
+
+impl GenerationRequestBuilder {
+    /// Service to use (defaults to the configured default service)
+    pub fn service(
+        mut self,
+        service: ServiceId,
+    ) -> Self {
+        self.service = Some(service);
+        self
+    }
+
+    /// Model alias or upstream name (defaults to the service's default model)
+    pub fn model(
+        mut self,
+        model: impl Into<ModelId>,
+    ) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn negative_prompt(
+        mut self,
+        negative_prompt: impl Into<String>,
+    ) -> Self {
+        self.negative_prompt = Some(negative_prompt.into());
+        self
+    }
+
+    pub fn width(
+        mut self,
+        width: u16,
+    ) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    pub fn height(
+        mut self,
+        height: u16,
+    ) -> Self {
+        self.height = Some(height);
+        self
+    }
+
+    /// Size snapped to the closest one the model accepts (instead of width and height)
+    pub fn size(
+        mut self,
+        size: Size,
+    ) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Aspect ratio resolved to the closest size the model accepts, keeping the area of its default size
+    pub fn aspect(
+        mut self,
+        aspect: AspectRatio,
+    ) -> Self {
+        self.aspect = Some(aspect);
+        self
+    }
+
+    pub fn steps(
+        mut self,
+        steps: u8,
+    ) -> Self {
+        self.steps = Some(steps);
+        self
+    }
+
+    pub fn cfg(
+        mut self,
+        cfg: f32,
+    ) -> Self {
+        self.cfg = Some(cfg);
+        self
+    }
+
+    pub fn seed(
+        mut self,
+        seed: u64,
+    ) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn style(
+        mut self,
+        style: OpenAIImageStyle,
+    ) -> Self {
+        self.style = Some(style);
+        self
+    }
+
+    pub fn sampler(
+        mut self,
+        sampler: impl Into<String>,
+    ) -> Self {
+        self.sampler = Some(sampler.into());
+        self
+    }
+
+    /// Image to start from (image-to-image)
+    pub fn init_image(
+        mut self,
+        init_image: impl Into<PathBuf>,
+    ) -> Self {
+        self.init_image = Some(init_image.into());
+        self
+    }
+
+    /// How much to change the init image (0-1)
+    pub fn strength(
+        mut self,
+        strength: f32,
+    ) -> Self {
+        self.strength = Some(strength);
+        self
+    }
+
+    /// PNG whose transparent areas are inpainted (requires an init image)
+    pub fn mask(
+        mut self,
+        mask: impl Into<PathBuf>,
+    ) -> Self {
+        self.mask = Some(mask.into());
+        self
+    }
+
+    pub fn quality(
+        mut self,
+        quality: OpenAIImageQuality,
+    ) -> Self {
+        self.quality = Some(quality);
+        self
+    }
+
+    pub fn background(
+        mut self,
+        background: OpenAIImageBackground,
+    ) -> Self {
+        self.background = Some(background);
+        self
+    }
+
+    /// Format the provider should return (GPT Image)
+    pub fn output_format(
+        mut self,
+        output_format: ImageFileFormat,
+    ) -> Self {
+        self.output_format = Some(output_format);
+        self
+    }
+
+    /// Compression level for JPEG and WebP (0-100)
+    pub fn compression(
+        mut self,
+        compression: u8,
+    ) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn moderation(
+        mut self,
+        moderation: OpenAIModeration,
+    ) -> Self {
+        self.moderation = Some(moderation);
+        self
+    }
+
+    /// Extra provider option sent as-is in the request body
+    pub fn option(
+        mut self,
+        key: impl Into<String>,
+        value: Value,
+    ) -> Self {
+        self.options.insert(key.into(), value);
+        self
+    }
+
+    /// Resolve the service and model from the services configuration
+    pub fn build(self) -> Result<GenerationRequest> {
+        if self.mask.is_some() && self.init_image.is_none() {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                "Mask requires an init image (request.rs)"
+            ));
+        }
+
+        if let Some(strength) = self.strength {
+            if self.init_image.is_none() {
+                bail!(CodedError::new(
+                    ErrorCode::InvalidRequest,
+                    "Strength requires an init image (request.rs)"
+                ));
+            }
+            if !(0.0..=1.0).contains(&strength) {
+                bail!(CodedError::new(
+                    ErrorCode::InvalidRequest,
+                    format!("Strength must be between 0 and 1, got {strength} (request.rs)")
+                ));
+            }
+        }
+
+        let exact = self.width.is_some() || self.height.is_some();
+        if [exact, self.size.is_some(), self.aspect.is_some()]
+            .iter()
+            .filter(|set| **set)
+            .count()
+            > 1
+        {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                "Use only one of width and height, size, or aspect ratio (request.rs)"
+            ));
+        }
+
+        let services = get_or_init_services();
+        let service = self.service.unwrap_or_else(|| services.default.id.clone());
+        let model = services.get(&service)?.resolve_model(self.model.as_ref())?;
+
+        // Sizes and aspect ratios snap to the model, while width and height are checked as given
+        let resolved = match (self.size, self.aspect) {
+            (Some(size), _) => Some(model.capabilities.closest_size(size)),
+            (_, Some(aspect)) => {
+                let default = match (model.width, model.height) {
+                    (Some(width), Some(height)) => Size::new(width, height),
+                    _ => Size::new(1024, 1024),
+                };
+                Some(model.capabilities.size_for_aspect(aspect, default))
+            }
+            _ => None,
+        };
+        let size_source = match (self.size, self.aspect) {
+            (Some(_), _) => SizeSource::Size,
+            (_, Some(_)) => SizeSource::Aspect,
+            _ => SizeSource::Exact,
+        };
+        let (width, height) = match resolved {
+            Some(size) => {
+                debug!("Resolved size {size} for `{}`", model.id);
+                (Some(size.width), Some(size.height))
+            }
+            None => (self.width, self.height),
+        };
+
+        let request = GenerationRequest {
+            service,
+            model,
+            prompt: self.prompt,
+            negative_prompt: self.negative_prompt,
+            width,
+            height,
+            size_source,
+            steps: self.steps,
+            cfg: self.cfg,
+            seed: self.seed,
+            style: self.style,
+            sampler: self.sampler,
+            init_image: self.init_image,
+            strength: self.strength,
+            mask: self.mask,
+            quality: self.quality,
+            background: self.background,
+            output_format: self.output_format,
+            compression: self.compression,
+            moderation: self.moderation,
+            options: self.options,
+        };
+
+        // Catch invalid parameters before they cost a request
+        request.model.capabilities.check(&request)?;
+        Ok(request)
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use image::guess_format;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use crate::request::GenerationRequest;
+
+/// A generated image with the parameters that produced it
+#[derive(Clone, Debug, Serialize)]
+pub struct GenerationResult {
+    #[serde(skip)]
+    pub image: Vec<u8>,
+    /// MIME type of the image bytes (e.g., `image/png`)
+    pub mime_type: String,
+    /// The request resolved against the model
+    pub request: GenerationRequest,
+    /// Request body sent to the provider
+    pub parameters: HashMap<String, Value>,
+    /// Seed returned by the provider or the requested seed
+    pub seed: Option<u64>,
+    /// Prompt rewritten by the provider (DALL-E 3)
+    pub revised_prompt: Option<String>,
+    /// URL the image was downloaded from (Together)
+    pub url: Option<String>,
+    /// Time taken by the provider request
+    #[serde(serialize_with = "serialize_secs")]
+    pub elapsed: Duration,
+}
+
+/// Serialize a duration as fractional seconds
+fn serialize_secs<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+impl GenerationResult {
+    /// Create a result with the MIME type from the response header or sniffed from the bytes
+    pub fn new(
+        image: Vec<u8>,
+        content_type: Option<&str>,
+        request: &GenerationRequest,
+        parameters: &HashMap<String, Value>,
+        elapsed: Duration,
+    ) -> Self {
+        let mime_type = content_type
+            .filter(|c| c.starts_with("image/"))
+            .map(str::to_string)
+            .or_else(|| guess_format(&image).ok().map(|f| f.to_mime_type().to_string()))
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+
+        Self {
+            image,
+            mime_type,
+            request: request.clone(),
+            parameters: parameters.clone(),
+            seed: request.seed,
+            revised_prompt: None,
+            url: None,
+            elapsed,
+        }
+    }
+}
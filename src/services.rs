@@ -1,11 +1,22 @@
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::OnceLock;
 
+use anyhow::{bail, Result};
+
 use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use strum::{Display, VariantNames};
 
+use crate::capabilities::{Capabilities, Size};
+use crate::config::{Config, CustomServiceConfig, ServiceConfig};
+use crate::error::{CodedError, ErrorCode};
+
 /// Enum for supported OpenAI image styles
 #[derive(Clone, Debug, Deserialize, Display, PartialEq, Serialize, ValueEnum, VariantNames)]
 #[serde(rename_all = "lowercase")]
@@ -15,24 +26,70 @@ pub enum OpenAIImageStyle {
     Vivid,
 }
 
-/// Enum for supported models
-#[derive(Clone, Debug, Deserialize, Display, PartialEq, Serialize, ValueEnum, VariantNames)]
-#[strum(serialize_all = "kebab-case")]
-#[serde(rename_all = "kebab-case")]
-pub enum ModelId {
-    Dalle2,
-    Dalle3,
-    Flux11Pro,
-    FluxPro,
-    FluxDev,
-    FluxSchnell,
-    Sd35Large,
-    Sd35LargeTurbo,
-    Sdxl,
+/// Enum for supported OpenAI image qualities
+/// DALL-E 3 takes `standard` or `hd`, while GPT Image takes `auto`, `low`, `medium`, or `high`
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Serialize, ValueEnum, VariantNames)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum OpenAIImageQuality {
+    Auto,
+    Low,
+    Medium,
+    High,
+    Standard,
+    Hd,
+}
+
+/// Enum for supported OpenAI image backgrounds (GPT Image)
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Serialize, ValueEnum, VariantNames)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum OpenAIImageBackground {
+    Auto,
+    Transparent,
+    Opaque,
+}
+
+/// Enum for supported OpenAI moderation levels (GPT Image)
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Serialize, ValueEnum, VariantNames)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum OpenAIModeration {
+    Auto,
+    Low,
+}
+
+/// Model alias defined per service (e.g., `flux-dev` or `user/my-lora-model`)
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct ModelId(pub String);
+
+impl fmt::Display for ModelId {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.pad(&self.0) // respect width and alignment flags
+    }
+}
+
+impl From<&str> for ModelId {
+    fn from(id: &str) -> Self {
+        Self(id.to_string())
+    }
+}
+
+impl FromStr for ModelId {
+    type Err = Infallible;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from(id))
+    }
 }
 
 /// Schema for a model configuration
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Model {
     pub id: ModelId,
     pub name: String,
@@ -43,29 +100,118 @@ pub struct Model {
     pub steps: Option<u8>,
     pub style: Option<OpenAIImageStyle>,
     pub negative_prompt: Option<String>,
+    /// Sampler name (AUTOMATIC1111)
+    pub sampler: Option<String>,
+    /// Workflow template (ComfyUI)
+    pub workflow: Option<Workflow>,
+    /// Limits checked before sending a request
+    #[serde(default)]
+    pub capabilities: Capabilities,
     // Misc settings
     #[serde(default)]
     pub options: Option<HashMap<String, serde_json::Value>>,
 }
 
-/// Enum for supported services
-#[derive(Clone, Debug, Deserialize, Display, PartialEq, Serialize, ValueEnum, VariantNames)]
+/// ComfyUI workflow in API format with the node inputs to substitute
+/// Inputs are written as `<node id>.<input name>` (e.g., `6.text`)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Workflow {
+    /// Workflow file exported with "Save (API Format)", relative to the config file
+    pub path: PathBuf,
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    pub seed: Option<String>,
+    pub steps: Option<String>,
+    pub cfg: Option<String>,
+    pub width: Option<String>,
+    pub height: Option<String>,
+    /// Receives the model name (e.g., a checkpoint loader's `ckpt_name`)
+    pub model: Option<String>,
+    /// Receives the uploaded init image (e.g., a `LoadImage` node's `image`)
+    pub image: Option<String>,
+    /// Receives the strength (e.g., a sampler's `denoise`)
+    pub strength: Option<String>,
+    /// Receives the uploaded mask (e.g., a `LoadImageMask` node's `image` with the `alpha` channel)
+    pub mask: Option<String>,
+}
+
+/// Service name, either built-in (`hf`, `openai`, `together`) or defined in the config
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(transparent)]
+pub struct ServiceId(pub String);
+
+impl fmt::Display for ServiceId {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.pad(&self.0) // respect width and alignment flags
+    }
+}
+
+impl From<&str> for ServiceId {
+    fn from(id: &str) -> Self {
+        Self(id.to_string())
+    }
+}
+
+impl FromStr for ServiceId {
+    type Err = Infallible;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from(id))
+    }
+}
+
+/// API a service speaks, which determines the client
+#[derive(Clone, Copy, Debug, Deserialize, Display, PartialEq, Serialize)]
 #[strum(serialize_all = "kebab-case")]
 #[serde(rename_all = "kebab-case")]
-pub enum ServiceId {
+pub enum ServiceKind {
     Hf,
     Openai,
     Together,
+    OpenaiCompatible,
+    Automatic1111,
+    Comfyui,
+}
+
+impl ServiceKind {
+    /// Whether unlisted model names are passed through as-is
+    pub fn accepts_any_model(&self) -> bool {
+        matches!(self, ServiceKind::OpenaiCompatible | ServiceKind::Automatic1111)
+    }
+
+    /// Whether services of this type can be defined in the config
+    pub fn is_configurable(&self) -> bool {
+        matches!(
+            self,
+            ServiceKind::OpenaiCompatible | ServiceKind::Automatic1111 | ServiceKind::Comfyui
+        )
+    }
+}
+
+/// How an OpenAI-compatible server returns images
+#[derive(Clone, Copy, Debug, Default, Deserialize, Display, PartialEq, Serialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    #[default]
+    B64Json,
+    Url,
 }
 
 /// Default service configuration
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct DefaultService {
     pub id: ServiceId,
 }
 
 /// Schema for a service's default model
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct DefaultModel {
     pub id: ModelId,
 }
@@ -74,6 +220,16 @@ pub struct DefaultModel {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Service {
     pub id: ServiceId,
+    #[serde(rename = "type")]
+    pub kind: ServiceKind,
+    /// API base URL override (e.g., a proxy or a local mock server)
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Environment variable with the API key (OpenAI-compatible servers may not need one)
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    #[serde(default)]
+    pub response_format: Option<ResponseFormat>,
     pub default: DefaultModel,
     pub models: Vec<Model>,
 }
@@ -82,161 +238,621 @@ pub struct Service {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Services {
     pub default: DefaultService,
-    pub hf: Service,
-    pub openai: Service,
-    pub together: Service,
+    pub services: Vec<Service>,
+}
+
+impl Model {
+    /// AUTOMATIC1111 model that uses whichever checkpoint is loaded in the WebUI
+    pub fn current_checkpoint() -> Self {
+        Self {
+            id: ModelId::from("current"),
+            name: String::new(),
+            height: Some(512),
+            width: Some(512),
+            cfg: Some(7.0),
+            steps: Some(20),
+            style: None,
+            negative_prompt: None,
+            sampler: Some("Euler a".to_string()),
+            workflow: None,
+            capabilities: Capabilities {
+                min_size: Some(64),
+                max_size: Some(2048),
+                size_multiple: Some(8),
+                min_steps: Some(1),
+                max_steps: Some(150),
+                min_cfg: Some(1.0),
+                max_cfg: Some(30.0),
+                ..Default::default()
+            },
+            options: None,
+        }
+    }
+
+    /// Create a model without defaults from an upstream name (e.g., a Hugging Face repo ID)
+    pub fn from_name(name: &str) -> Self {
+        Self {
+            id: ModelId::from(name),
+            name: name.to_string(),
+            height: None,
+            width: None,
+            cfg: None,
+            steps: None,
+            style: None,
+            negative_prompt: None,
+            sampler: None,
+            workflow: None,
+            capabilities: Capabilities::default(),
+            options: None,
+        }
+    }
+}
+
+impl Service {
+    /// Find a model by alias or upstream name
+    pub fn find_model(
+        &self,
+        id: &ModelId,
+    ) -> Option<&Model> {
+        self.models
+            .iter()
+            .find(|m| m.id == *id)
+            .or_else(|| self.models.iter().find(|m| m.name == id.0))
+    }
+
+    /// Resolve a model alias (or the service default) to a model config
+    /// Unknown Hugging Face repo IDs (e.g., `user/model`) and model names for OpenAI-compatible and
+    /// AUTOMATIC1111 services are used as-is without defaults
+    pub fn resolve_model(
+        &self,
+        id: Option<&ModelId>,
+    ) -> Result<Model> {
+        let model_id = id.unwrap_or(&self.default.id);
+        if let Some(model) = self.find_model(model_id) {
+            return Ok(model.clone());
+        }
+        match self.kind {
+            ServiceKind::Hf if model_id.0.contains('/') => return Ok(Model::from_name(&model_id.0)),
+            kind if kind.accepts_any_model() => return Ok(Model::from_name(&model_id.0)),
+            _ => {}
+        }
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "Unsupported model `{model_id}` for service `{}`, see `--list-models` (services.rs)",
+                self.id
+            )
+        ))
+    }
+
+    /// Environment variable that overrides the API base URL of a built-in service
+    fn base_url_env(&self) -> Option<&'static str> {
+        match self.kind {
+            ServiceKind::Hf => Some("HF_BASE_URL"),
+            ServiceKind::Openai => Some("OPENAI_BASE_URL"),
+            ServiceKind::Together => Some("TOGETHER_BASE_URL"),
+            // Only the built-in instance of a self-hosted service reads the environment
+            ServiceKind::Automatic1111 if self.id.0 == "a1111" => Some("A1111_BASE_URL"),
+            ServiceKind::Automatic1111 | ServiceKind::OpenaiCompatible | ServiceKind::Comfyui => None,
+        }
+    }
+
+    /// Get the API base URL from the environment, the config, or the built-in default (in that order)
+    pub fn get_base_url(
+        &self,
+        default: &str,
+    ) -> Result<String> {
+        let base_url = self
+            .base_url_env()
+            .and_then(|name| env::var(name).ok())
+            .filter(|v| !v.is_empty())
+            .or_else(|| self.base_url.clone())
+            .unwrap_or_else(|| default.to_string());
+        if let Err(e) = reqwest::Url::parse(&base_url) {
+            bail!(CodedError::new(
+                ErrorCode::Config,
+                format!("Invalid base URL `{base_url}` for `{}`: {e} (services.rs)", self.id)
+            ));
+        }
+        // Endpoints are appended with a leading slash
+        Ok(base_url.trim_end_matches('/').to_string())
+    }
+
+    /// Create a service from a user-defined config
+    /// Without a default model, the first model is the default
+    fn from_custom(
+        id: ServiceId,
+        config: CustomServiceConfig,
+    ) -> Result<Self> {
+        if !config.kind.is_configurable() {
+            bail!(CodedError::new(
+                ErrorCode::Config,
+                format!("Unsupported type `{}` for service `{id}` (services.rs)", config.kind)
+            ));
+        }
+
+        // AUTOMATIC1111 can always use the loaded checkpoint
+        let mut models = config.models;
+        if models.is_empty() && config.kind == ServiceKind::Automatic1111 {
+            models.push(Model::current_checkpoint());
+        }
+
+        // ComfyUI models are workflows
+        if config.kind == ServiceKind::Comfyui {
+            if let Some(model) = models.iter().find(|m| m.workflow.is_none()) {
+                bail!(CodedError::new(
+                    ErrorCode::Config,
+                    format!("Model `{}` of service `{id}` needs a workflow (services.rs)", model.id)
+                ));
+            }
+        }
+
+        let default = match (config.default, models.first()) {
+            (Some(default), _) => default,
+            (None, Some(model)) => DefaultModel { id: model.id.clone() },
+            (None, None) => bail!(CodedError::new(
+                ErrorCode::Config,
+                format!("Service `{id}` needs a default model or at least one model (services.rs)")
+            )),
+        };
+
+        Ok(Self {
+            id,
+            kind: config.kind,
+            base_url: Some(config.base_url),
+            api_key_env: config.api_key_env,
+            response_format: config.response_format,
+            default,
+            models,
+        })
+    }
+
+    /// Merge a service override into this service
+    /// Models with an existing ID are replaced and new models are appended
+    pub fn merge(
+        &mut self,
+        config: ServiceConfig,
+    ) {
+        if let Some(default) = config.default {
+            self.default = default;
+        }
+        if let Some(base_url) = config.base_url {
+            self.base_url = Some(base_url);
+        }
+        for model in config.models {
+            match self.models.iter_mut().find(|m| m.id == model.id) {
+                Some(existing) => *existing = model,
+                None => self.models.push(model),
+            }
+        }
+    }
+
+    /// Ensure the default model exists in the model list
+    /// Services that accept any model name may have an unlisted default
+    fn validate(&self) -> Result<()> {
+        if !self.kind.accepts_any_model() && !self.models.iter().any(|m| m.id == self.default.id) {
+            bail!(CodedError::new(
+                ErrorCode::Config,
+                format!(
+                    "Default model `{}` not found in `{}` models (services.rs)",
+                    self.default.id, self.id
+                )
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Services {
+    /// Iterate over the services
+    pub fn iter(&self) -> impl Iterator<Item = &Service> {
+        self.services.iter()
+    }
+
+    /// Get the configuration for a service
+    pub fn get(
+        &self,
+        id: &ServiceId,
+    ) -> Result<&Service> {
+        self.services.iter().find(|s| s.id == *id).ok_or_else(|| {
+            CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!("Unknown service `{id}`, see `--list-services` (services.rs)"),
+            )
+            .into()
+        })
+    }
+
+    /// Merge a user configuration over these services
+    /// User-defined services are appended and can't replace a built-in service
+    pub fn merge(
+        &mut self,
+        config: Config,
+    ) -> Result<()> {
+        if let Some(default) = config.default {
+            self.default = default;
+        }
+        for (id, service_config) in [
+            ("hf", config.hf),
+            ("openai", config.openai),
+            ("together", config.together),
+            ("a1111", config.a1111),
+        ] {
+            if let Some(service_config) = service_config {
+                if let Some(service) = self.services.iter_mut().find(|s| s.id.0 == id) {
+                    service.merge(service_config);
+                }
+            }
+        }
+        for (id, custom) in config.services {
+            if self.services.iter().any(|s| s.id == id) {
+                bail!(CodedError::new(
+                    ErrorCode::Config,
+                    format!("Service `{id}` is already defined (services.rs)")
+                ));
+            }
+            self.services.push(Service::from_custom(id, custom)?);
+        }
+
+        for service in &self.services {
+            service.validate()?;
+        }
+        if self.get(&self.default.id).is_err() {
+            bail!(CodedError::new(
+                ErrorCode::Config,
+                format!("Default service `{}` not found (services.rs)", self.default.id)
+            ));
+        }
+        Ok(())
+    }
+}
+
+static SERVICES: OnceLock<Services> = OnceLock::new();
+
+/// Initialize the services with the user configuration merged over the defaults
+/// Must be called before `get_or_init_services` to take effect
+pub fn init_services(config: Option<Config>) -> Result<&'static Services> {
+    let mut services = default_services();
+    if let Some(config) = config {
+        services.merge(config)?;
+    }
+    if SERVICES.set(services).is_err() {
+        bail!("Services already initialized (services.rs)");
+    }
+    Ok(get_or_init_services())
 }
 
 /// Get or initialize the default services configuration
 pub fn get_or_init_services() -> &'static Services {
-    static SERVICES: OnceLock<Services> = OnceLock::new();
-    SERVICES.get_or_init(|| Services {
-        default: DefaultService { id: ServiceId::Hf },
-        hf: Service {
-            id: ServiceId::Hf,
-            default: DefaultModel {
-                id: ModelId::Sd35LargeTurbo,
-            },
-            models: vec![
-                Model {
-                    id: ModelId::Sd35LargeTurbo,
-                    name: "stabilityai/stable-diffusion-3.5-large-turbo".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: Some(4),
-                    cfg: None,
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
-                },
-                Model {
-                    id: ModelId::Sd35Large,
-                    name: "stabilityai/stable-diffusion-3.5-large".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: Some(28),
-                    cfg: Some(3.5),
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
-                },
-                Model {
-                    id: ModelId::Sdxl,
-                    name: "stabilityai/stable-diffusion-xl-base-1.0".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: Some(50),
-                    cfg: Some(7.5),
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
-                },
-                Model {
-                    id: ModelId::FluxSchnell,
-                    name: "black-forest-labs/FLUX.1-schnell".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: Some(4),
-                    cfg: None,
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
-                },
-                Model {
-                    id: ModelId::FluxDev,
-                    name: "black-forest-labs/FLUX.1-dev".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: Some(28),
-                    cfg: Some(3.0),
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
-                },
-            ],
+    SERVICES.get_or_init(default_services)
+}
+
+/// Built-in services configuration
+fn default_services() -> Services {
+    Services {
+        default: DefaultService {
+            id: ServiceId::from("hf"),
         },
-        openai: Service {
-            id: ServiceId::Openai,
-            default: DefaultModel { id: ModelId::Dalle3 },
-            models: vec![
-                Model {
-                    id: ModelId::Dalle3,
-                    name: "dall-e-3".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: None,
-                    cfg: None,
-                    style: Some(OpenAIImageStyle::Vivid),
-                    negative_prompt: None,
-                    options: Some(HashMap::from([(
-                        "quality".to_string(),
-                        Value::String("standard".to_string()),
-                    )])),
+        services: vec![
+            Service {
+                id: ServiceId::from("hf"),
+                kind: ServiceKind::Hf,
+                base_url: None,
+                api_key_env: None,
+                response_format: None,
+                default: DefaultModel {
+                    id: ModelId::from("sd35-large-turbo"),
                 },
-                Model {
-                    id: ModelId::Dalle2,
-                    name: "dall-e-2".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: None,
-                    cfg: None,
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
-                },
-            ],
-        },
-        together: Service {
-            id: ServiceId::Together,
-            default: DefaultModel {
-                id: ModelId::FluxSchnell,
+                models: vec![
+                    Model {
+                        id: ModelId::from("sd35-large-turbo"),
+                        name: "stabilityai/stable-diffusion-3.5-large-turbo".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: Some(4),
+                        cfg: None,
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(512),
+                            max_size: Some(1536),
+                            size_multiple: Some(64),
+                            min_steps: Some(1),
+                            max_steps: Some(10),
+                            min_cfg: Some(0.0),
+                            max_cfg: Some(10.0),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                    Model {
+                        id: ModelId::from("sd35-large"),
+                        name: "stabilityai/stable-diffusion-3.5-large".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: Some(28),
+                        cfg: Some(3.5),
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(512),
+                            max_size: Some(1536),
+                            size_multiple: Some(64),
+                            min_steps: Some(1),
+                            max_steps: Some(100),
+                            min_cfg: Some(0.0),
+                            max_cfg: Some(20.0),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                    Model {
+                        id: ModelId::from("sdxl"),
+                        name: "stabilityai/stable-diffusion-xl-base-1.0".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: Some(50),
+                        cfg: Some(7.5),
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(512),
+                            max_size: Some(2048),
+                            size_multiple: Some(8),
+                            min_steps: Some(1),
+                            max_steps: Some(100),
+                            min_cfg: Some(0.0),
+                            max_cfg: Some(20.0),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                    Model {
+                        id: ModelId::from("flux-schnell"),
+                        name: "black-forest-labs/FLUX.1-schnell".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: Some(4),
+                        cfg: None,
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(256),
+                            max_size: Some(1440),
+                            size_multiple: Some(16),
+                            min_steps: Some(1),
+                            max_steps: Some(50),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                    Model {
+                        id: ModelId::from("flux-dev"),
+                        name: "black-forest-labs/FLUX.1-dev".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: Some(28),
+                        cfg: Some(3.0),
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(256),
+                            max_size: Some(1440),
+                            size_multiple: Some(16),
+                            min_steps: Some(1),
+                            max_steps: Some(50),
+                            min_cfg: Some(0.0),
+                            max_cfg: Some(20.0),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                ],
             },
-            models: vec![
-                Model {
-                    id: ModelId::FluxSchnell,
-                    name: "black-forest-labs/FLUX.1-schnell-Free".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: Some(4),
-                    cfg: None,
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
+            Service {
+                id: ServiceId::from("openai"),
+                kind: ServiceKind::Openai,
+                base_url: None,
+                api_key_env: None,
+                response_format: None,
+                default: DefaultModel {
+                    id: ModelId::from("dalle3"),
                 },
-                Model {
-                    id: ModelId::FluxDev,
-                    name: "black-forest-labs/FLUX.1-dev".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: Some(28),
-                    cfg: Some(3.0),
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
-                },
-                Model {
-                    id: ModelId::FluxPro,
-                    name: "black-forest-labs/FLUX.1-pro".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: Some(40),
-                    cfg: Some(2.5),
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
+                models: vec![
+                    Model {
+                        id: ModelId::from("gpt-image-1"),
+                        name: "gpt-image-1".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: None,
+                        cfg: None,
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            sizes: vec![Size::new(1024, 1024), Size::new(1536, 1024), Size::new(1024, 1536)],
+                            seed: false,
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                    Model {
+                        id: ModelId::from("dalle3"),
+                        name: "dall-e-3".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: None,
+                        cfg: None,
+                        style: Some(OpenAIImageStyle::Vivid),
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            sizes: vec![Size::new(1024, 1024), Size::new(1792, 1024), Size::new(1024, 1792)],
+                            seed: false,
+                            styles: vec![OpenAIImageStyle::Vivid, OpenAIImageStyle::Natural],
+                            ..Default::default()
+                        },
+                        options: Some(HashMap::from([(
+                            "quality".to_string(),
+                            Value::String("standard".to_string()),
+                        )])),
+                    },
+                    Model {
+                        id: ModelId::from("dalle2"),
+                        name: "dall-e-2".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: None,
+                        cfg: None,
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            sizes: vec![Size::new(256, 256), Size::new(512, 512), Size::new(1024, 1024)],
+                            seed: false,
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                ],
+            },
+            Service {
+                id: ServiceId::from("together"),
+                kind: ServiceKind::Together,
+                base_url: None,
+                api_key_env: None,
+                response_format: None,
+                default: DefaultModel {
+                    id: ModelId::from("flux-schnell"),
                 },
-                Model {
-                    id: ModelId::Flux11Pro,
-                    name: "black-forest-labs/FLUX.1.1-pro".to_string(),
-                    height: Some(1024),
-                    width: Some(1024),
-                    steps: None,
-                    cfg: None,
-                    style: None,
-                    negative_prompt: None,
-                    options: None,
+                models: vec![
+                    Model {
+                        id: ModelId::from("flux-schnell"),
+                        name: "black-forest-labs/FLUX.1-schnell-Free".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: Some(4),
+                        cfg: None,
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(256),
+                            max_size: Some(1792),
+                            size_multiple: Some(16),
+                            min_steps: Some(1),
+                            max_steps: Some(4),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                    Model {
+                        id: ModelId::from("flux-dev"),
+                        name: "black-forest-labs/FLUX.1-dev".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: Some(28),
+                        cfg: Some(3.0),
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(256),
+                            max_size: Some(1792),
+                            size_multiple: Some(16),
+                            min_steps: Some(1),
+                            max_steps: Some(50),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                    Model {
+                        id: ModelId::from("flux-pro"),
+                        name: "black-forest-labs/FLUX.1-pro".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: Some(40),
+                        cfg: Some(2.5),
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(256),
+                            max_size: Some(1440),
+                            size_multiple: Some(32),
+                            min_steps: Some(1),
+                            max_steps: Some(50),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                    Model {
+                        id: ModelId::from("flux11-pro"),
+                        name: "black-forest-labs/FLUX.1.1-pro".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: None,
+                        cfg: None,
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(256),
+                            max_size: Some(1440),
+                            size_multiple: Some(32),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                    Model {
+                        id: ModelId::from("flux-kontext-pro"),
+                        name: "black-forest-labs/FLUX.1-kontext-pro".to_string(),
+                        height: Some(1024),
+                        width: Some(1024),
+                        steps: None,
+                        cfg: None,
+                        style: None,
+                        negative_prompt: None,
+                        sampler: None,
+                        workflow: None,
+                        capabilities: Capabilities {
+                            min_size: Some(256),
+                            max_size: Some(1440),
+                            size_multiple: Some(32),
+                            ..Default::default()
+                        },
+                        options: None,
+                    },
+                ],
+            },
+            Service {
+                id: ServiceId::from("a1111"),
+                kind: ServiceKind::Automatic1111,
+                base_url: None,
+                api_key_env: None,
+                response_format: None,
+                default: DefaultModel {
+                    id: ModelId::from("current"),
                 },
-            ],
-        },
-    })
+                models: vec![Model::current_checkpoint()],
+            },
+        ],
+    }
 }
@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::debug;
+use serde::Serialize;
+
+use crate::metadata::ImageMetadata;
+use crate::result::GenerationResult;
+use crate::services::{ModelId, ServiceId};
+
+/// JSON manifest written next to a generated image
+#[derive(Debug, Serialize)]
+pub struct Sidecar<'a> {
+    /// Image file the manifest describes
+    pub file: &'a str,
+    /// RFC 3339 timestamp of when the image was saved
+    pub created_at: String,
+    /// Seconds since the command started
+    pub elapsed: f64,
+    pub service: &'a ServiceId,
+    /// Model alias
+    pub model: &'a ModelId,
+    /// Upstream model name
+    pub model_name: &'a str,
+    /// Parameters resolved against the model defaults
+    pub resolved: ImageMetadata,
+    /// Provider response extras and the request body sent
+    pub result: &'a GenerationResult,
+}
+
+impl<'a> Sidecar<'a> {
+    pub fn new(
+        file: &'a str,
+        result: &'a GenerationResult,
+        elapsed: Duration,
+    ) -> Self {
+        let created_at: DateTime<Utc> = Utc::now();
+        Self {
+            file,
+            created_at: created_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            elapsed: elapsed.as_secs_f64(),
+            service: &result.request.service,
+            model: &result.request.model.id,
+            model_name: &result.request.model.name,
+            resolved: ImageMetadata::from(result),
+            result,
+        }
+    }
+}
+
+/// Write a sidecar manifest next to the image (e.g., `image.png` to `image.png.json`)
+/// The image extension is kept so `image.png` and `image.webp` don't share a manifest
+pub fn write_sidecar(
+    file: &str,
+    result: &GenerationResult,
+    elapsed: Duration,
+) -> Result<String> {
+    let path = PathBuf::from(format!("{file}.json"));
+    let sidecar = Sidecar::new(file, result, elapsed);
+    let json = serde_json::to_string_pretty(&sidecar)?;
+
+    debug!("Writing {} to disk", path.display());
+    fs::write(&path, json).context(format!("Failed to save sidecar to {} (sidecar.rs)", path.display()))?;
+    Ok(path.to_string_lossy().into_owned())
+}
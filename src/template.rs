@@ -0,0 +1,174 @@
+use anyhow::{bail, Result};
+use chrono::Local;
+use image::ImageFormat;
+
+use crate::error::{CodedError, ErrorCode};
+use crate::result::GenerationResult;
+
+/// Counter placeholder, replaced by the image number
+pub const COUNTER: &str = "{n}";
+
+/// Line placeholder, replaced by the prompts file line number before rendering
+pub const LINE: &str = "{line}";
+
+/// Maximum length of `{prompt_slug}` so paths stay well under filesystem limits
+const SLUG_MAX_LEN: usize = 48;
+
+/// Render an output path template like `out/{date}/{service}-{model}-{seed}.{ext}`
+///
+/// Placeholders: `{date}`, `{service}`, `{model}`, `{seed}`, `{prompt_slug}`, `{ext}`, and `{n}`
+/// (`{line}` is replaced by batches beforehand)
+pub fn render_path(
+    template: &str,
+    result: &GenerationResult,
+    n: usize,
+    format: Option<ImageFormat>,
+) -> Result<String> {
+    let mut path = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        path.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!("Unclosed placeholder in `{template}` (template.rs)")
+            ));
+        };
+
+        let value = match &rest[start + 1..start + end] {
+            "date" => Local::now().format("%Y-%m-%d").to_string(),
+            "service" => result.request.service.to_string(),
+            "model" => sanitize(&result.request.model.id.0),
+            "seed" => result.seed.map_or_else(|| "random".to_string(), |s| s.to_string()),
+            "prompt_slug" => slugify(&result.request.prompt),
+            "ext" => format.unwrap_or(ImageFormat::Png).extensions_str()[0].to_string(),
+            "n" => format!("{n:03}"),
+            "line" => bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!("`{{line}}` in `{template}` is only available with --prompts-file (template.rs)")
+            )),
+            name => bail!(CodedError::new(
+                ErrorCode::InvalidRequest,
+                format!("Unknown placeholder `{{{name}}}` in `{template}` (template.rs)")
+            )),
+        };
+        path.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    path.push_str(rest);
+
+    Ok(path)
+}
+
+/// Replace path separators so a value can't create directories (e.g., Hugging Face repo IDs)
+fn sanitize(value: &str) -> String {
+    value.replace(['/', '\\'], "_")
+}
+
+/// Lowercase ASCII words joined by hyphens (e.g., `A cat, in space!` to `a-cat-in-space`)
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for word in text
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        if !slug.is_empty() && slug.len() + word.len() + 1 > SLUG_MAX_LEN {
+            break;
+        }
+        if !slug.is_empty() {
+            slug.push('-');
+        }
+        slug.push_str(&word.to_ascii_lowercase());
+    }
+    slug.truncate(SLUG_MAX_LEN);
+
+    if slug.is_empty() {
+        "image".to_string()
+    } else {
+        slug
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::error::error_code;
+    use crate::request::GenerationRequest;
+
+    fn result(seed: Option<u64>) -> GenerationResult {
+        let mut builder = GenerationRequest::builder("A cat, in space!");
+        if let Some(seed) = seed {
+            builder = builder.seed(seed);
+        }
+        let request = builder.build().unwrap();
+        GenerationResult::new(Vec::new(), None, &request, &HashMap::new(), Duration::ZERO)
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let path = render_path(
+            "out/{service}/{model}-{seed}-{prompt_slug}-{n}.{ext}",
+            &result(Some(42)),
+            2,
+            Some(ImageFormat::Jpeg),
+        )
+        .unwrap();
+        assert_eq!(path, "out/hf/sd35-large-turbo-42-a-cat-in-space-002.jpg");
+    }
+
+    #[test]
+    fn renders_defaults() {
+        let path = render_path("{seed}.{ext}", &result(None), 1, None).unwrap();
+        assert_eq!(path, "random.png");
+
+        let date = Local::now().format("%Y-%m-%d").to_string();
+        assert_eq!(render_path("{date}", &result(None), 1, None).unwrap(), date);
+    }
+
+    #[test]
+    fn keeps_paths_without_placeholders() {
+        assert_eq!(
+            render_path("out/image.png", &result(None), 1, None).unwrap(),
+            "out/image.png"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_placeholder() {
+        let e = render_path("out/{name}.png", &result(None), 1, None).unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::InvalidRequest);
+        assert!(e.to_string().contains("Unknown placeholder `{name}`"), "{e}");
+    }
+
+    #[test]
+    fn rejects_line_outside_batches() {
+        let e = render_path("out/{line}.png", &result(None), 1, None).unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::InvalidRequest);
+        assert!(e.to_string().contains("--prompts-file"), "{e}");
+    }
+
+    #[test]
+    fn rejects_unclosed_placeholder() {
+        let e = render_path("out/{seed.png", &result(None), 1, None).unwrap_err();
+        assert_eq!(error_code(&e), ErrorCode::InvalidRequest);
+        assert!(e.to_string().contains("Unclosed placeholder"), "{e}");
+    }
+
+    #[test]
+    fn sanitizes_separators() {
+        assert_eq!(sanitize("org/repo\\name"), "org_repo_name");
+    }
+
+    #[test]
+    fn slugifies_text() {
+        assert_eq!(slugify("A cat, in space!"), "a-cat-in-space");
+        assert_eq!(slugify("日本語 !!"), "image");
+
+        let slug = slugify(&"word ".repeat(20));
+        assert!(slug.len() <= SLUG_MAX_LEN);
+        assert!(!slug.ends_with('-'));
+    }
+}
@@ -1,49 +1,217 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Cursor, Write};
 use std::path::Path;
 use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
-use image::{load_from_memory, ImageFormat};
+use image::{guess_format, load_from_memory, ImageFormat};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use indicatif_log_bridge::LogWrapper;
 use log::debug;
 use simplelog::{ColorChoice, Config as LogConfig, LevelFilter, TermLogger, TerminalMode};
 
-/// Writes the image bytes to a file
-pub fn write_image(
-    path: &str,
-    image_bytes: &[u8],
-) -> Result<String> {
-    let base = Path::new(path)
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .context(format!("`{path}` is not a valid (util.rs)"))?;
+use crate::error::{CodedError, ErrorCode};
+use crate::metadata::{embed_metadata, ImageMetadata};
+use crate::result::GenerationResult;
+use crate::template::{render_path, COUNTER};
+
+/// Output path that writes to stdout
+pub const STDOUT: &str = "-";
+
+/// Highest suffix tried by `--no-clobber` before giving up
+const MAX_CLOBBER_SUFFIX: usize = 9999;
 
+/// Get the image format from a file extension
+fn format_from_path(path: &str) -> Result<ImageFormat> {
     let ext = Path::new(path)
         .extension()
         .and_then(|e| e.to_str())
         .context(format!("`{path}` has no extension (util.rs)"))?;
 
-    let (file, format) = match ext {
-        "jpg" => (format!("{base}.jpg"), ImageFormat::Jpeg),
-        "jpeg" => (format!("{base}.jpeg"), ImageFormat::Jpeg),
-        "png" => (format!("{base}.png"), ImageFormat::Png),
-        "webp" => (format!("{base}.webp"), ImageFormat::WebP),
-        _ => bail!("Unsupported image format `{ext}` (util.rs)"),
+    match ext {
+        "jpg" | "jpeg" => Ok(ImageFormat::Jpeg),
+        "png" => Ok(ImageFormat::Png),
+        "webp" => Ok(ImageFormat::WebP),
+        _ => bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            format!("Unsupported image format `{ext}` (util.rs)")
+        )),
+    }
+}
+
+/// Error if an explicit format disagrees with the path's extension
+/// Templates get the extension from `{ext}`, so a mismatch means the extension was written out by hand
+pub fn check_path_format(
+    path: &str,
+    format: Option<ImageFormat>,
+) -> Result<()> {
+    let Some(format) = format.filter(|_| path != STDOUT) else {
+        return Ok(());
     };
+    if let Some(ext_format) = format_from_path(path).ok().filter(|f| *f != format) {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "`{path}` has a {} extension but the format is {}, use `{{ext}}` in the path or drop `--format` (util.rs)",
+                ext_format.extensions_str()[0],
+                format.extensions_str()[0]
+            )
+        ));
+    }
+    Ok(())
+}
 
-    debug!("Decoding {file}");
-    let dynamic_image = load_from_memory(image_bytes).context("Failed to decode image (util.rs)")?;
+/// Writes the image bytes to a file (or stdout for `-`), embedding the metadata if provided
+/// The format is inferred from the extension unless given (PNG for stdout)
+/// Missing parent directories are created, and with `no_clobber` an existing file gets a suffix instead
+pub fn write_image(
+    path: &str,
+    image_bytes: &[u8],
+    format: Option<ImageFormat>,
+    metadata: Option<&ImageMetadata>,
+    no_clobber: bool,
+) -> Result<String> {
+    // Keep the parent directory so outputs can be written next to their inputs
+    let file = path.to_string();
+    let format = match format {
+        Some(format) => format,
+        None if path == STDOUT => ImageFormat::Png,
+        None => format_from_path(path)?,
+    };
+
+    check_path_format(path, Some(format))?;
 
-    debug!("Writing {file} to disk");
-    dynamic_image
-        .save_with_format(&file, format)
-        .context(format!("Failed to save image to {file} (util.rs)"))?;
+    // Keep lossy images the provider already encoded in the right format instead of compressing them twice
+    let is_lossy = matches!(format, ImageFormat::Jpeg | ImageFormat::WebP);
+    let mut encoded = if is_lossy && guess_format(image_bytes).ok() == Some(format) {
+        debug!("Keeping the provider's encoding for {file}");
+        image_bytes.to_vec()
+    } else {
+        debug!("Decoding {file}");
+        let dynamic_image = load_from_memory(image_bytes).context("Failed to decode image (util.rs)")?;
+
+        debug!("Encoding {file}");
+        let mut encoded = Vec::new();
+        dynamic_image
+            .write_to(&mut Cursor::new(&mut encoded), format)
+            .context(format!("Failed to encode {file} (util.rs)"))?;
+        encoded
+    };
+
+    if let Some(metadata) = metadata {
+        debug!("Embedding metadata in {file}");
+        encoded = embed_metadata(encoded, format, metadata)?;
+    }
 
-    Ok(file)
+    if path == STDOUT {
+        debug!("Writing image to stdout");
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(&encoded)
+            .context("Failed to write image to stdout (util.rs)")?;
+        stdout.flush()?;
+        return Ok(file);
+    }
+
+    if let Some(parent) = Path::new(&file).parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).context(format!("Failed to create {} (util.rs)", parent.display()))?;
+    }
+
+    if !no_clobber {
+        debug!("Writing {file} to disk");
+        fs::write(&file, encoded).context(format!("Failed to save image to {file} (util.rs)"))?;
+        return Ok(file);
+    }
+
+    // Reserve the file with `create_new` so concurrent batch entries can't claim the same name
+    for i in 0..=MAX_CLOBBER_SUFFIX {
+        let candidate = if i == 0 { file.clone() } else { suffixed_path(&file, i) };
+        match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(mut f) => {
+                debug!("Writing {candidate} to disk");
+                f.write_all(&encoded)
+                    .context(format!("Failed to save image to {candidate} (util.rs)"))?;
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).context(format!("Failed to create {candidate} (util.rs)")),
+        }
+    }
+    bail!(CodedError::new(
+        ErrorCode::Io,
+        format!("Too many existing files named like {file} (util.rs)")
+    ))
+}
+
+/// Writes each image to the rendered path template, numbering the paths when there is more than one
+/// and the template has no `{n}`
+pub fn write_images(
+    path: &str,
+    results: &[GenerationResult],
+    format: Option<ImageFormat>,
+    with_metadata: bool,
+    no_clobber: bool,
+) -> Result<Vec<String>> {
+    if results.len() > 1 && path == STDOUT {
+        bail!(CodedError::new(
+            ErrorCode::InvalidRequest,
+            "Only one image can be written to stdout (util.rs)"
+        ));
+    }
+    let numbered = results.len() > 1 && !path.contains(COUNTER);
+
+    results
+        .iter()
+        .enumerate()
+        .map(|(i, result)| {
+            let metadata = with_metadata.then(|| ImageMetadata::from(result));
+            let mut file = if path == STDOUT {
+                path.to_string()
+            } else {
+                render_path(path, result, i + 1, format)?
+            };
+            if numbered {
+                file = numbered_path(&file, i + 1);
+            }
+            write_image(&file, &result.image, format, metadata.as_ref(), no_clobber)
+        })
+        .collect()
+}
+
+/// Append a suffix to the file stem (e.g., `image.png` to `image-1.png`)
+fn suffixed_path(
+    path: &str,
+    suffix: usize,
+) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+    let file = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{stem}-{suffix}.{ext}"),
+        None => format!("{stem}-{suffix}"),
+    };
+    path.with_file_name(file).to_string_lossy().into_owned()
+}
+
+/// Append a zero-padded number to the file stem (e.g., `image.png` to `image-001.png`)
+pub fn numbered_path(
+    path: &str,
+    number: usize,
+) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+    let file = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{stem}-{number:03}.{ext}"),
+        None => format!("{stem}-{number:03}"),
+    };
+    path.with_file_name(file).to_string_lossy().into_owned()
 }
 
 /// Initialize the logger with debug level
-pub fn init_logger(is_debug: bool) -> Result<MultiProgress> {
+/// Logs go to stderr only when stdout is reserved for output (images or JSON)
+pub fn init_logger(
+    is_debug: bool,
+    stderr_only: bool,
+) -> Result<MultiProgress> {
     let multi_progress = MultiProgress::new();
     let logger = TermLogger::new(
         if is_debug {
@@ -52,7 +220,11 @@ pub fn init_logger(is_debug: bool) -> Result<MultiProgress> {
             LevelFilter::Warn
         },
         LogConfig::default(),
-        TerminalMode::Mixed,
+        if stderr_only {
+            TerminalMode::Stderr
+        } else {
+            TerminalMode::Mixed
+        },
         ColorChoice::Auto,
     );
 
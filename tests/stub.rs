@@ -0,0 +1,653 @@
+//! End-to-end runs of the binary against a stub provider on localhost
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use image::{DynamicImage, ImageFormat, RgbImage};
+use serde_json::Value;
+use txt2img::read_parameters;
+
+/// Upstream name of the default Hugging Face model
+const MODEL_PATH: &str = "/stabilityai/stable-diffusion-3.5-large-turbo";
+
+/// A request received by the stub
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(
+        &self,
+        name: &str,
+    ) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A canned response
+struct Reply {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn png() -> Self {
+        let mut body = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(8, 8))
+            .write_to(&mut Cursor::new(&mut body), ImageFormat::Png)
+            .unwrap();
+        Self {
+            status: 200,
+            headers: vec![("Content-Type", "image/png".to_string())],
+            body,
+        }
+    }
+
+    /// OpenAI response with the image inline
+    fn openai_b64() -> Self {
+        let image = STANDARD.encode(Self::png().body);
+        Self::json(
+            200,
+            serde_json::json!({ "created": 0, "data": [{ "b64_json": image }] }),
+        )
+    }
+
+    fn json(
+        status: u16,
+        body: Value,
+    ) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "application/json".to_string())],
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn error(
+        status: u16,
+        message: &str,
+    ) -> Self {
+        Self::json(status, serde_json::json!({ "error": message }))
+    }
+
+    fn retry_after(
+        mut self,
+        seconds: u64,
+    ) -> Self {
+        self.headers.push(("Retry-After", seconds.to_string()));
+        self
+    }
+}
+
+/// HTTP server that answers each connection with the next reply, then stops listening
+struct Stub {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Stub {
+    fn start(replies: Vec<Reply>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = Arc::clone(&requests);
+        thread::spawn(move || {
+            for reply in replies {
+                let Ok((stream, _)) = listener.accept() else {
+                    return;
+                };
+                let request = read_request(&stream);
+                received.lock().unwrap().push(request);
+                write_reply(stream, reply);
+            }
+        });
+        Self { url, requests }
+    }
+
+    fn requests(&self) -> Vec<Request> {
+        std::mem::take(&mut *self.requests.lock().unwrap())
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Request {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let length = headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    Request {
+        method,
+        path,
+        headers,
+        body,
+    }
+}
+
+fn write_reply(
+    mut stream: TcpStream,
+    reply: Reply,
+) {
+    let mut head = format!(
+        "HTTP/1.1 {} Stub\r\nContent-Length: {}\r\nConnection: close\r\n",
+        reply.status,
+        reply.body.len()
+    );
+    for (key, value) in &reply.headers {
+        head.push_str(&format!("{key}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(&reply.body).unwrap();
+}
+
+/// Empty directory for the config, cache, and outputs of a test
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("txt2img-stub-{}-{name}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The binary with an isolated home directory and no provider keys
+fn command(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_txt2img"));
+    command
+        .env_clear()
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("XDG_CACHE_HOME", dir.join("cache"));
+    command
+}
+
+/// Run the binary with Hugging Face pointed at the stub
+fn txt2img(
+    dir: &Path,
+    base_url: &str,
+    args: &[&str],
+) -> Output {
+    command(dir)
+        .args(args)
+        .env("HF_TOKEN", "test-token")
+        .env("HF_BASE_URL", base_url)
+        .output()
+        .unwrap()
+}
+
+/// Run the binary with OpenAI pointed at the stub
+fn openai(
+    dir: &Path,
+    base_url: &str,
+    args: &[&str],
+) -> Output {
+    command(dir)
+        .args(["--service", "openai"])
+        .args(args)
+        .env("OPENAI_API_KEY", "test-key")
+        .env("OPENAI_BASE_URL", base_url)
+        .output()
+        .unwrap()
+}
+
+/// Write a PNG to use as an init image
+fn write_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+) {
+    DynamicImage::ImageRgb8(RgbImage::new(width, height))
+        .save_with_format(path, ImageFormat::Png)
+        .unwrap();
+}
+
+fn json(output: &Output) -> Value {
+    serde_json::from_slice(&output.stdout)
+        .unwrap_or_else(|e| panic!("{e}: {}", String::from_utf8_lossy(&output.stdout)))
+}
+
+fn error_code(output: &Output) -> String {
+    assert_eq!(
+        output.status.code(),
+        Some(1),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    json(output)["error"]["code"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn generates_image() {
+    let dir = temp_dir("generates");
+    let stub = Stub::start(vec![Reply::png()]);
+    let out = dir.join("cat.png");
+
+    let output = txt2img(
+        &dir,
+        &stub.url,
+        &[
+            "-q",
+            "--output",
+            "json",
+            "--seed",
+            "42",
+            "-o",
+            out.to_str().unwrap(),
+            "a cat",
+        ],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let result = json(&output);
+    assert_eq!(result["images"][0]["path"], out.to_str().unwrap());
+    assert_eq!(result["images"][0]["service"], "hf");
+    assert_eq!(result["images"][0]["seed"], 42);
+
+    let parameters = read_parameters(&fs::read(&out).unwrap()).unwrap().unwrap();
+    assert!(parameters.starts_with("a cat\n"), "{parameters}");
+    assert!(parameters.contains("Seed: 42"), "{parameters}");
+
+    let requests = stub.requests();
+    assert_eq!(requests.len(), 1);
+    let request = &requests[0];
+    assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", MODEL_PATH));
+    assert_eq!(request.header("authorization"), Some("Bearer test-token"));
+    let body: Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(body["inputs"], "a cat");
+    assert_eq!(body["parameters"]["seed"], 42);
+}
+
+#[test]
+fn retries_rate_limits_and_gateway_errors() {
+    let dir = temp_dir("retries");
+    let stub = Stub::start(vec![
+        Reply::error(429, "Too many requests").retry_after(0),
+        Reply::error(502, "Bad gateway").retry_after(0),
+        Reply::error(503, "Service unavailable").retry_after(0),
+        Reply::png(),
+    ]);
+    let out = dir.join("cat.png");
+
+    let output = txt2img(&dir, &stub.url, &["-q", "-o", out.to_str().unwrap(), "a cat"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(out.exists());
+    assert_eq!(stub.requests().len(), 4);
+}
+
+#[test]
+fn waits_for_retry_after() {
+    let dir = temp_dir("retry-after");
+    let stub = Stub::start(vec![
+        Reply::error(429, "Too many requests").retry_after(1),
+        Reply::png(),
+    ]);
+    let out = dir.join("cat.png");
+
+    let start = Instant::now();
+    let output = txt2img(&dir, &stub.url, &["-q", "-o", out.to_str().unwrap(), "a cat"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert_eq!(stub.requests().len(), 2);
+}
+
+#[test]
+fn gives_up_after_retries() {
+    let dir = temp_dir("gives-up");
+    let stub = Stub::start(vec![
+        Reply::error(503, "Service unavailable").retry_after(0),
+        Reply::error(503, "Service unavailable").retry_after(0),
+    ]);
+
+    let output = txt2img(&dir, &stub.url, &["-q", "--output", "json", "--retries", "1", "a cat"]);
+    assert_eq!(error_code(&output), "provider");
+    assert_eq!(stub.requests().len(), 2);
+}
+
+#[test]
+fn maps_provider_errors() {
+    let dir = temp_dir("provider");
+    let stub = Stub::start(vec![Reply::error(400, "Invalid prompt")]);
+
+    let output = txt2img(&dir, &stub.url, &["-q", "--output", "json", "a cat"]);
+    assert_eq!(error_code(&output), "provider");
+    let message = json(&output)["error"]["message"].as_str().unwrap().to_string();
+    assert!(message.contains("Invalid prompt"), "{message}");
+    // Client errors aren't retried
+    assert_eq!(stub.requests().len(), 1);
+}
+
+#[test]
+fn maps_network_errors() {
+    let dir = temp_dir("network");
+    // Nothing listens on the port once the listener is dropped
+    let url = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+
+    let output = txt2img(&dir, &url, &["-q", "--output", "json", "--retries", "0", "a cat"]);
+    assert_eq!(error_code(&output), "network");
+}
+
+#[test]
+fn maps_timeouts() {
+    let dir = temp_dir("timeout");
+    // Accept connections but never answer
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let output = txt2img(
+        &dir,
+        &url,
+        &["-q", "--output", "json", "--retries", "0", "--timeout", "1", "a cat"],
+    );
+    assert_eq!(error_code(&output), "timeout");
+    drop(listener);
+}
+
+#[test]
+fn maps_missing_api_keys() {
+    let dir = temp_dir("api-key");
+    let output = command(&dir)
+        .args(["-q", "--output", "json", "a cat"])
+        .output()
+        .unwrap();
+    assert_eq!(error_code(&output), "missing_api_key");
+}
+
+#[test]
+fn maps_invalid_requests() {
+    let dir = temp_dir("invalid");
+    let stub = Stub::start(Vec::new());
+
+    let output = txt2img(&dir, &stub.url, &["-q", "--output", "json", "--steps", "200", "a cat"]);
+    assert_eq!(error_code(&output), "invalid_request");
+    assert!(stub.requests().is_empty());
+}
+
+#[test]
+fn rejects_ignored_prompt_under_strict() {
+    let dir = temp_dir("variations-strict");
+    let stub = Stub::start(Vec::new());
+    let init = dir.join("init.png");
+    write_png(&init, 8, 8);
+
+    let output = openai(
+        &dir,
+        &stub.url,
+        &[
+            "-q",
+            "--output",
+            "json",
+            "--strict",
+            "-m",
+            "dalle2",
+            "--init-image",
+            init.to_str().unwrap(),
+            "a cat",
+        ],
+    );
+    assert_eq!(error_code(&output), "invalid_request");
+    let message = json(&output)["error"]["message"].as_str().unwrap().to_string();
+    assert!(message.contains("ignores the prompt"), "{message}");
+    assert!(stub.requests().is_empty());
+}
+
+#[test]
+fn fails_lines_with_colliding_outputs() {
+    let dir = temp_dir("collisions");
+    let stub = Stub::start(vec![Reply::png()]);
+    let out = dir.join("cat.png");
+    let prompts = dir.join("prompts.txt");
+    let line = serde_json::json!({ "prompt": "a cat", "out": out }).to_string();
+    fs::write(&prompts, format!("{line}\n{line}\n")).unwrap();
+
+    let output = txt2img(
+        &dir,
+        &stub.url,
+        &["-q", "--output", "json", "--prompts-file", prompts.to_str().unwrap()],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let summary = json(&output);
+    assert_eq!(summary["error"]["code"], "batch_failed");
+    assert_eq!(summary["succeeded"][0]["line"], 1);
+    assert_eq!(summary["failed"][0]["line"], 2);
+    assert_eq!(summary["failed"][0]["code"], "invalid_request");
+    let message = summary["failed"][0]["message"].as_str().unwrap();
+    assert!(message.contains("already used by line 1"), "{message}");
+    assert!(out.exists());
+    assert_eq!(stub.requests().len(), 1);
+}
+
+#[test]
+fn names_the_size_option_when_ignored() {
+    let dir = temp_dir("ignored-size");
+    let config = dir.join("config.toml");
+    fs::write(
+        &config,
+        "[[together.models]]\nid = \"no-size\"\nname = \"user/no-size\"\n",
+    )
+    .unwrap();
+
+    for (args, flag) in [
+        (["--width", "512"], "--width"),
+        (["--size", "512x512"], "--size"),
+        (["--aspect", "16:9"], "--aspect"),
+    ] {
+        let output = command(&dir)
+            .args([
+                "-q",
+                "--output",
+                "json",
+                "--strict",
+                "--config",
+                config.to_str().unwrap(),
+            ])
+            .args(["--service", "together", "-m", "no-size"])
+            .args(args)
+            .arg("a cat")
+            .env("TOGETHER_API_KEY", "test-key")
+            .output()
+            .unwrap();
+        assert_eq!(error_code(&output), "invalid_request");
+        let message = json(&output)["error"]["message"].as_str().unwrap().to_string();
+        assert!(message.ends_with(&format!("ignores {flag} (mod.rs)")), "{message}");
+    }
+}
+
+#[test]
+fn warns_about_ignored_prompt() {
+    let dir = temp_dir("variations");
+    let stub = Stub::start(vec![Reply::openai_b64()]);
+    let init = dir.join("init.png");
+    write_png(&init, 8, 8);
+    let out = dir.join("cat.png");
+
+    let output = openai(
+        &dir,
+        &stub.url,
+        &[
+            "-m",
+            "dalle2",
+            "--init-image",
+            init.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "a cat",
+        ],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // Warnings are logged to stdout unless it's reserved for the image or JSON
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("ignores the prompt"), "{stdout}");
+    assert!(out.exists());
+
+    let requests = stub.requests();
+    assert_eq!(requests[0].path, "/images/variations");
+}
+
+#[test]
+fn rejects_non_square_dalle2_images() {
+    let dir = temp_dir("dalle2-square");
+    let stub = Stub::start(Vec::new());
+    let init = dir.join("init.png");
+    write_png(&init, 12, 8);
+
+    let output = openai(
+        &dir,
+        &stub.url,
+        &[
+            "-q",
+            "--output",
+            "json",
+            "-m",
+            "dalle2",
+            "--init-image",
+            init.to_str().unwrap(),
+            "a cat",
+        ],
+    );
+    assert_eq!(error_code(&output), "invalid_request");
+    let message = json(&output)["error"]["message"].as_str().unwrap().to_string();
+    assert!(message.contains("square image, got 12x8"), "{message}");
+    assert!(stub.requests().is_empty());
+}
+
+#[test]
+fn rejects_large_dalle2_images() {
+    let dir = temp_dir("dalle2-large");
+    let stub = Stub::start(Vec::new());
+    // Noise doesn't compress, so this is over 4 MB as PNG
+    let mut state = 1u32;
+    let noise = RgbImage::from_fn(1280, 1280, |_, _| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        let [r, g, b, _] = state.to_le_bytes();
+        [r, g, b].into()
+    });
+    let init = dir.join("init.png");
+    noise.save_with_format(&init, ImageFormat::Png).unwrap();
+
+    let output = openai(
+        &dir,
+        &stub.url,
+        &[
+            "-q",
+            "--output",
+            "json",
+            "-m",
+            "dalle2",
+            "--init-image",
+            init.to_str().unwrap(),
+            "a cat",
+        ],
+    );
+    assert_eq!(error_code(&output), "invalid_request");
+    let message = json(&output)["error"]["message"].as_str().unwrap().to_string();
+    assert!(
+        message.contains("under 4 MB") && message.contains("1280x1280"),
+        "{message}"
+    );
+    assert!(stub.requests().is_empty());
+}
+
+#[test]
+fn downloads_result_urls_without_api_key() {
+    let dir = temp_dir("download");
+    let cdn = Stub::start(vec![Reply::png()]);
+    let image_url = format!("{}/image.png", cdn.url);
+    let stub = Stub::start(vec![Reply::json(
+        200,
+        serde_json::json!({ "created": 0, "data": [{ "url": image_url }] }),
+    )]);
+    let out = dir.join("cat.png");
+
+    let output = openai(&dir, &stub.url, &["-q", "-o", out.to_str().unwrap(), "a cat"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(out.exists());
+
+    assert_eq!(stub.requests()[0].header("authorization"), Some("Bearer test-key"));
+    let downloads = cdn.requests();
+    assert_eq!(downloads[0].path, "/image.png");
+    assert_eq!(downloads[0].header("authorization"), None);
+}
+
+#[test]
+fn prints_subcommands_as_json() {
+    let dir = temp_dir("subcommands");
+    let stub = Stub::start(vec![Reply::png()]);
+    let out = dir.join("cat.png");
+    let output = txt2img(
+        &dir,
+        &stub.url,
+        &["-q", "--seed", "7", "-o", out.to_str().unwrap(), "a cat"],
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = command(&dir)
+        .args(["inspect", "--output", "json", out.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let inspections = json(&output);
+    assert_eq!(inspections[0]["metadata"]["prompt"], "a cat");
+    assert_eq!(inspections[0]["metadata"]["seed"], 7);
+
+    let output = command(&dir)
+        .args(["--output", "json", "cache", "ls"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(json(&output), serde_json::json!([]));
+
+    // A missing image fails with a single JSON document
+    let missing = dir.join("missing.png");
+    let output = command(&dir)
+        .args([
+            "inspect",
+            "--output",
+            "json",
+            out.to_str().unwrap(),
+            missing.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(error_code(&output), "invalid_request");
+}